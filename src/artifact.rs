@@ -0,0 +1,31 @@
+use crate::{
+  battle::HeroStats,
+  skill::Passive
+};
+use serde::{ Serialize, Deserialize };
+
+// An artifact adds flat ATK and HP after gear, and procs: passives that come
+// with the artifact instead of the hero, run by `battle::react` like any other
+// once given to the picker with `AISkillPicker::with_artifact`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Artifact {
+  pub name: String,
+  #[serde(default)]
+  pub atk: f32,
+  #[serde(default)]
+  pub hp: f32,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub procs: Vec<Passive>,
+}
+
+impl Artifact {
+  // `stats` with the artifact's lines on top. Not scaled by gear percentages.
+  pub fn apply(&self, stats: &HeroStats) -> HeroStats {
+    let mut stats = stats.clone();
+    stats.atk += self.atk;
+    stats.max_hp += self.hp;
+    stats.hp += self.hp;
+    stats
+  }
+}
@@ -1,16 +1,25 @@
 use crate::{
+  artifact::Artifact,
   rng::*,
-  skill::*
+  skill::*,
+  combat_log::*,
+  condition::ConditionContext
 };
+use serde::{ Serialize, Deserialize };
 
 #[derive(Clone)]
 pub struct Hero {
+  pub name: String,
   pub stats: HeroStats,
+  // `stats` without effect modifiers, see `refresh_stat`.
+  pub base_stats: HeroStats,
 
   pub alive: bool,
   pub readiness: u32,
   pub effects: Effects,
   pub team: u32,
+  // Turns left before each skill slot is ready, see `Skill::cooldown`.
+  pub cooldowns: Vec<u32>,
 }
 
 #[derive(Clone)]
@@ -27,46 +36,102 @@ pub struct HeroStats {
   pub element: Element,
   pub hit_chance: f32,
   pub crit_resist: f32,
+  // Chance in percent to counter when attacked, see `counter_attack`.
+  pub counter: f32,
+  // Percent of damage dealt by any attack healed back, e.g. from gear sets.
+  pub lifesteal: f32,
 }
 
 impl Hero {
-  fn add_stat_change(&mut self, kind: StatKind, modifier: f32) {
-    *self.member_for_stat(kind) += modifier;
+  pub fn skill_ready(&self, slot: usize) -> bool {
+    self.cooldowns.get(slot).is_none_or(|&x| x == 0)
   }
 
-  fn remove_stat_change(&mut self, kind: StatKind, modifier: f32) {
-    *self.member_for_stat(kind) -= modifier;
+  fn put_on_cooldown(&mut self, slot: usize, turns: u32) {
+    if self.cooldowns.len() <= slot {
+      self.cooldowns.resize(slot + 1, 0);
+    }
+    self.cooldowns[slot] = turns;
   }
 
-  fn member_for_stat(&mut self, stat: StatKind) -> &mut f32 {
-    use StatKind::*;
+  fn tick_cooldowns(&mut self) {
+    self.cooldowns.iter_mut().for_each(|x| *x = x.saturating_sub(1));
+  }
 
-    match stat {
-      ATK => &mut self.stats.atk,
-      SPD => &mut self.stats.spd,
-      DEF => &mut self.stats.def,
-      CC => &mut self.stats.cc,
-      CDmg => &mut self.stats.cdmg,
-      HitChance => &mut self.stats.hit_chance,
-      CritResist => &mut self.stats.crit_resist,
-      EFF => &mut self.stats.eff,
-      EffRes => &mut self.stats.effres,
+  // Recompute the `kind` stat from `base_stats` and every modifier of that
+  // kind still active. Modifiers add up, so a buff and a debuff of the same
+  // size cancel out. ATK, DEF and SPD are scaled by the total, percentage
+  // stats move by it in percentage points.
+  fn refresh_stat(&mut self, kind: StatKind) {
+    let total: f32 = self.effects.arr.iter().filter_map(|x| match x.effect.get_kind() {
+      EffectKind::StatModifier(stat, modifier) if stat == kind => Some(modifier),
+      _ => None,
+    }).sum();
+    let base = *self.base_stats.member_for_stat(kind);
+    *self.stats.member_for_stat(kind) = if kind.is_percent() { base + total * 100.0 } else { base * (1.0 + total) };
+  }
+
+  fn has_modifier(&self, kind: StatKind) -> bool {
+    self.effects.arr.iter().any(|x| matches!(x.effect.get_kind(), EffectKind::StatModifier(stat, _) if stat == kind))
+  }
+
+  // Returns false if the effect was blocked or there was no room for it.
+  pub fn apply_effect(&mut self, effect: Effect, duration: u32, source: Option<HeroID>) -> bool {
+    // While nothing modifies the stat it may have been set directly, e.g. by
+    // gear or a speed sweep, so take it as the new base.
+    if let EffectKind::StatModifier(kind, _) = effect.get_kind() {
+      if !self.has_modifier(kind) {
+        *self.base_stats.member_for_stat(kind) = *self.stats.member_for_stat(kind);
+      }
+    }
+
+    match self.effects.apply(effect, duration, source) {
+      Applied::Rejected => return false,
+      Applied::Refreshed => return true,
+      Applied::Added => (),
+      Applied::Replaced(old) => self.refresh_stats_of(old),
     }
+    self.refresh_stats_of(effect);
+    true
   }
 
-  fn apply_effect(&mut self, effect: Effect, duration: u32) {
-    let (success, removed) = self.effects.apply(effect, duration);
+  // Stunned, frozen or asleep.
+  pub fn can_act(&self) -> bool {
+    use Effect::*;
+    ![Stun, Freeze, Sleep, Daydream].iter().any(|&x| self.effects.has_effect(x))
+  }
 
-    if let Some(removed) = removed {
-      self.undo_stat_changes(removed)
-    };
+  // Provoked heroes and heroes in a rage or silenced can only use their first
+  // skill. Provoke and rage also force its target.
+  fn restricted(&self) -> (bool, Option<HeroID>) {
+    use Effect::*;
+    let forced = self.effects.arr.iter().find(|x| matches!(x.effect, Provoke | Rage)).and_then(|x| x.source);
+    let restricted = [Provoke, Rage, Silence].iter().any(|&x| self.effects.has_effect(x));
+    (restricted, forced)
+  }
 
-    if success {
-      match effect.get_kind() {
-        EffectKind::StatModifier(stat, modf) => self.add_stat_change(stat, modf),
-        EffectKind::Simple => (),
-      }
+  // Remove up to `count` dispellable effects of `polarity`, newest first.
+  pub fn dispel(&mut self, polarity: Polarity, count: u32) -> Vec<Effect> {
+    let removed = self.effects.dispel(polarity, count);
+    for &effect in &removed {
+      self.refresh_stats_of(effect);
     }
+    removed
+  }
+
+  // Damage from an enemy wakes a sleeping hero.
+  fn wake_up(&mut self) {
+    self.consume_effects(|entry| if matches!(entry.effect, Effect::Sleep | Effect::Daydream) { entry.duration } else { 0 });
+  }
+
+  // Effects other than damage and healing over time, which tick at turn
+  // start, wear off by a turn at the end of the holder's turn.
+  fn tick_effects(&mut self) {
+    use Effect::*;
+    self.consume_effects(|entry| match entry.effect {
+      Burn(_) | Bleed(_) | Poison | ContinuousHealing | Regen(_) => 0,
+      _ => 1,
+    });
   }
 
   fn reduce_effect_duration(&mut self, num_turns: u32) {
@@ -74,11 +139,11 @@ impl Hero {
     // removed.iter().for_each(|&x| self.on_effect_removed(x));
   }
 
-  fn undo_stat_changes(&mut self, effect: Effect) {
-    use EffectKind::*;
-    match effect.get_kind() {
-      StatModifier(stat, modf) => self.remove_stat_change(stat, modf),
-      Simple => (),
+  // Bring the stat `effect` modifies, if any, up to date after it was added
+  // or removed.
+  fn refresh_stats_of(&mut self, effect: Effect) {
+    if let EffectKind::StatModifier(kind, _) = effect.get_kind() {
+      self.refresh_stat(kind);
     }
   }
 
@@ -87,11 +152,30 @@ impl Hero {
       .effects
       .consume_effects(f)
       .iter()
-      .for_each(|removed| self.undo_stat_changes(removed.effect));
+      .for_each(|removed| self.refresh_stats_of(removed.effect));
   }
 }
 
-#[derive(Copy, Clone)]
+impl HeroStats {
+  fn member_for_stat(&mut self, stat: StatKind) -> &mut f32 {
+    use StatKind::*;
+
+    match stat {
+      ATK => &mut self.atk,
+      SPD => &mut self.spd,
+      DEF => &mut self.def,
+      CC => &mut self.cc,
+      CDmg => &mut self.cdmg,
+      HitChance => &mut self.hit_chance,
+      CritResist => &mut self.crit_resist,
+      EFF => &mut self.eff,
+      EffRes => &mut self.effres,
+    }
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Element {
   Fire,
   Ice,
@@ -100,7 +184,7 @@ pub enum Element {
   Dark,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 enum StatKind {
   SPD,
   ATK,
@@ -113,7 +197,14 @@ enum StatKind {
   CritResist,
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+impl StatKind {
+  fn is_percent(&self) -> bool {
+    !matches!(self, StatKind::ATK | StatKind::DEF | StatKind::SPD)
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Effect {
   AtkBuff,
   AtkDown,
@@ -127,15 +218,37 @@ pub enum Effect {
   Immunity,
   Unhealable,
   ContinuousHealing,
-  Bleed(f32),
-  Burn(f32),
+  Bleed(DotAmount),
+  Burn(DotAmount),
   Poison,
   Rage,
   Daydream,
   Invincible,
   SkillNull,
+  // Counters every enemy attack while it lasts.
+  Counter,
+  // Heals this much at the start of each turn.
+  Regen(DotAmount),
+  // Barrier absorbing this much damage before HP. Applying another adds to
+  // the pool.
+  Shield(f32),
+  // Skip turns. Sleep, like `Daydream`, ends when hit by an enemy.
+  Stun,
+  Sleep,
+  Freeze,
+  // Only the first skill, aimed at whoever applied the provoke.
+  Provoke,
+  // Only the first skill.
+  Silence,
+  // Damage can't take HP below 1 while it lasts.
+  Immortal,
 }
 
+// Damage a bleed or burn deals, or healing a regen gives, each turn. Skills
+// give an ATK rate instead, see `SkillEffect`.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct DotAmount(pub f32);
+
 enum EffectKind {
   StatModifier(StatKind, f32),
   Simple,
@@ -149,6 +262,7 @@ impl Effect {
     match self {
       GreaterAtk => StatModifier(ATK, 0.75),
       AtkBuff => StatModifier(ATK, 0.5),
+      AtkDown => StatModifier(ATK, -0.5),
       DefBuff => StatModifier(DEF, 0.6),
       DefBreak => StatModifier(DEF, -0.7),
       SpdBuff => StatModifier(SPD, 0.3),
@@ -158,6 +272,29 @@ impl Effect {
     }
   }
 
+  // Share of the target's DEF ignored by a tick of damage over time, see
+  // `DOT_DEF_PEN`. Poison is a cut of max HP and ignores DEF altogether.
+  fn dot_def_pen(&self) -> f32 {
+    match self {
+      Effect::Bleed(_) | Effect::Burn(_) => DOT_DEF_PEN,
+      _ => 1.0,
+    }
+  }
+
+  pub fn polarity(&self) -> Polarity {
+    use Effect::*;
+
+    match self {
+      AtkDown | DefBreak | SpdDown | CannotBuff | Unhealable | Bleed(_) | Burn(_) | Poison | Rage | Daydream
+        | Stun | Sleep | Freeze | Provoke | Silence => Polarity::Debuff,
+      _ => Polarity::Buff,
+    }
+  }
+
+  pub fn is_debuff(&self) -> bool {
+    self.polarity() == Polarity::Debuff
+  }
+
   fn can_dispel(&self) -> bool {
     use Effect::*;
 
@@ -171,28 +308,63 @@ impl Effect {
     use Effect::*;
 
     match self {
-      Burn(_) | Bleed(_) | Poison | ContinuousHealing => true,
+      Burn(_) | Bleed(_) | Poison | ContinuousHealing | Regen(_) => true,
       _ => false,
     }
   }
 
+  // Which of two effects holds the slot they compete for. See `EFFECT_TIERS`.
   fn get_priority(first: Effect, second: Effect) -> EffectPriority {
-    use Effect::*;
-
-    match (first, second) {
-      (AtkBuff, GreaterAtk) => EffectPriority::First,
-      (GreaterAtk, AtkBuff) => EffectPriority::Second,
+    if std::mem::discriminant(&first) == std::mem::discriminant(&second) {
+      return EffectPriority::Same;
+    }
+    let rank = |x: Effect| EFFECT_TIERS.iter().enumerate().find_map(|(tier, effects)| {
+      effects.iter().position(|&y| y == x).map(|rank| (tier, rank))
+    });
+    match (rank(first), rank(second)) {
+      (Some((a, x)), Some((b, y))) if a == b => {
+        if x > y { EffectPriority::First } else { EffectPriority::Second }
+      }
       _ => EffectPriority::NoOverwrite,
     }
   }
 }
 
+// Versions of an effect that share a slot, weakest first. A stronger version
+// replaces a weaker one and a weaker one can't be applied over a stronger.
+// Greater attack is the only effect here with a weaker version; the other
+// stat buffs and debuffs have a single strength, and control effects like
+// stun and sleep each hold their own slot.
+const EFFECT_TIERS: &[&[Effect]] = &[
+  &[Effect::AtkBuff, Effect::GreaterAtk],
+];
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Polarity {
+  Buff,
+  Debuff,
+}
+
 enum EffectPriority {
   First,
   Second,
+  // The same effect, which refreshes unless it stacks.
+  Same,
   NoOverwrite,
 }
 
+// Outcome of `Effects::apply`.
+enum Applied {
+  Added,
+  // Took the place of a weaker version of itself.
+  Replaced(Effect),
+  // Already there; the longer of the two durations is kept.
+  Refreshed,
+  // Blocked, outranked or no room left.
+  Rejected,
+}
+
 #[derive(Clone)]
 pub struct Effects {
   arr: Vec<EffectEntry>,
@@ -202,52 +374,110 @@ pub struct Effects {
 struct EffectEntry {
   effect: Effect,
   duration: u32,
+  source: Option<HeroID>,
+}
+
+impl EffectEntry {
+  // Damage of one turn of bleed, burn or poison on a hero with `max_hp`.
+  fn tick_damage(&self, max_hp: f32) -> DamageInstance {
+    match self.effect {
+      Effect::Burn(DotAmount(dmg)) | Effect::Bleed(DotAmount(dmg)) => DamageInstance { raw_dmg: dmg, def_pen: self.effect.dot_def_pen(), fixed: 0.0 },
+      Effect::Poison => DamageInstance { raw_dmg: max_hp * 0.05, def_pen: self.effect.dot_def_pen(), fixed: 0.0 },
+      _ => DamageInstance { raw_dmg: 0.0, def_pen: 0.0, fixed: 0.0 },
+    }
+  }
 }
 
-const EFFECT_MAX_COUNT: usize = 10;
+const BUFF_MAX_COUNT: usize = 10;
+const DEBUFF_MAX_COUNT: usize = 10;
+// Stacks of one damage or healing over time effect a single hero can keep on
+// a target.
+const DOT_MAX_STACKS: usize = 5;
+// Bleed and burn go through the usual damage formula like an attack would,
+// but mostly past DEF.
+const DOT_DEF_PEN: f32 = 0.7;
 
 impl Effects {
   pub fn new() -> Self {
     Effects { arr: vec![] }
   }
 
-  fn apply(&mut self, effect: Effect, duration: u32) -> (bool, Option<Effect>) {
-    let mut entry = EffectEntry {
-      effect: effect,
-      duration: duration,
-    };
-    let eff = self;
-
-    if !effect.can_stack() {
-      let exist = eff.arr.iter().position(|entry| {
-        matches!(
-          Effect::get_priority(effect, entry.effect),
-          EffectPriority::First
-        )
-      });
+  fn apply(&mut self, effect: Effect, duration: u32, source: Option<HeroID>) -> Applied {
+    let entry = EffectEntry { effect, duration, source };
 
-      if let Some(exist) = exist {
-        let temp = eff.arr[exist].effect;
-        entry.duration = u32::max(entry.duration, duration);
-        eff.arr[exist] = entry;
+    let blocked = if effect.is_debuff() { Effect::Immunity } else { Effect::CannotBuff };
+    if self.has_effect(blocked) {
+      return Applied::Rejected;
+    }
 
-        return (true, Some(temp));
+    if effect.can_stack() {
+      // The oldest stack from the same source makes way for the new one.
+      let kind = std::mem::discriminant(&effect);
+      let stacks: Vec<usize> = (0..self.arr.len())
+        .filter(|&i| std::mem::discriminant(&self.arr[i].effect) == kind && self.arr[i].source == source)
+        .collect();
+      if stacks.len() >= DOT_MAX_STACKS {
+        self.arr.remove(stacks[0]);
+        self.arr.push(entry);
+        return Applied::Added;
+      }
+    } else {
+      for i in 0..self.arr.len() {
+        match Effect::get_priority(effect, self.arr[i].effect) {
+          // Replaced and refreshed effects count as just applied, so they
+          // move to the back and are the first to be dispelled.
+          EffectPriority::First => {
+            let old = self.arr.remove(i);
+            self.arr.push(entry);
+            return Applied::Replaced(old.effect);
+          }
+          EffectPriority::Second => return Applied::Rejected,
+          EffectPriority::Same => {
+            let mut existing = self.arr.remove(i);
+            existing.duration = u32::max(existing.duration, duration);
+            existing.source = source;
+            if let (Effect::Shield(pool), Effect::Shield(more)) = (&mut existing.effect, effect) {
+              *pool += more;
+            }
+            self.arr.push(existing);
+            return Applied::Refreshed;
+          }
+          EffectPriority::NoOverwrite => (),
+        }
       }
     }
 
-    if eff.arr.len() < EFFECT_MAX_COUNT {
-      eff.arr.push(entry);
-
-      (true, None)
-    } else {
-      (false, None)
+    let polarity = effect.polarity();
+    let max = match polarity {
+      Polarity::Buff => BUFF_MAX_COUNT,
+      Polarity::Debuff => DEBUFF_MAX_COUNT,
+    };
+    if self.count(polarity) >= max {
+      return Applied::Rejected;
     }
+    self.arr.push(entry);
+    Applied::Added
+  }
+
+  pub fn entries<'a>(&'a self) -> impl Iterator<Item = (Effect, u32)> + 'a {
+    self.arr.iter().map(|x| (x.effect, x.duration))
+  }
+
+  // Effects of `polarity`, counting every stack.
+  pub fn count(&self, polarity: Polarity) -> usize {
+    self.arr.iter().filter(|x| x.effect.polarity() == polarity).count()
   }
 
   fn has_effect(&self, effect: Effect) -> bool {
     self.arr.iter().find(|&x| x.effect == effect).is_some()
   }
 
+  // Like `has_effect` but ignores the value carried by effects like `Bleed`.
+  pub fn has_effect_kind(&self, effect: Effect) -> bool {
+    let kind = std::mem::discriminant(&effect);
+    self.arr.iter().any(|x| std::mem::discriminant(&x.effect) == kind)
+  }
+
   fn get_bleed_effects<'a>(&'a self) -> impl Iterator<Item = &EffectEntry> + 'a {
     self.arr.iter().filter(|x| {
       matches!(
@@ -257,16 +487,32 @@ impl Effects {
     })
   }
 
-  fn dispel(&mut self, num_debuffs: u32) -> Vec<Effect> {
-    let mut i = 0;
+  // Take as much of `dmg` as shields can, draining and removing them.
+  // Returns the damage absorbed.
+  fn absorb(&mut self, dmg: f32) -> f32 {
+    let mut absorbed = 0.0;
+    for entry in &mut self.arr {
+      if let Effect::Shield(ref mut pool) = entry.effect {
+        let take = f32::min(*pool, dmg - absorbed);
+        *pool -= take;
+        absorbed += take;
+      }
+    }
+    self.arr.retain(|x| !matches!(x.effect, Effect::Shield(pool) if pool <= 0.0));
+    absorbed
+  }
+
+  // Effects are kept in the order they were applied, so the most recent
+  // ones are at the back.
+  fn dispel(&mut self, polarity: Polarity, count: u32) -> Vec<Effect> {
     let mut removed = Vec::<Effect>::new();
-    while i != self.arr.len() && removed.len() < num_debuffs as usize {
-      let entry = &self.arr[i];
-      if entry.effect.can_dispel() {
-        removed.push(entry.effect);
+    let mut i = self.arr.len();
+    while i > 0 && removed.len() < count as usize {
+      i -= 1;
+      let effect = self.arr[i].effect;
+      if effect.polarity() == polarity && effect.can_dispel() {
+        removed.push(effect);
         self.arr.remove(i);
-      } else {
-        i += 1;
       }
     }
     removed
@@ -284,7 +530,7 @@ impl Effects {
     while i != self.arr.len() {
       let entry = &mut self.arr[i];
       let turns_consumed = f(entry);
-      entry.duration -= turns_consumed;
+      entry.duration = entry.duration.saturating_sub(turns_consumed);
       if entry.duration < 1 {
         removed.push(self.arr.remove(i));
       } else {
@@ -298,34 +544,179 @@ impl Effects {
 #[derive(Clone)]
 pub struct BattleSnapshot {
   pub heroes: Vec<Hero>,
-  pub base_stats: Vec<Hero>,
+  pub turn: u32,
+  pub log: CombatLog,
+  pub skill_use: SkillUse,
+  // Waiting for passives to react to them, see `react`.
+  pub triggers: Vec<TriggerEvent>,
+  // Hero to act next regardless of CR, see `battle`.
+  pub extra_turn: Option<HeroID>,
+  // Hero whose turn it is.
+  pub acting: Option<HeroID>,
+  // Slots of `once` passives that already triggered, per hero.
+  pub passives_used: Vec<(HeroID, usize)>,
+}
+
+// Something passives can react to. `by` is the hero responsible, if any.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TriggerEvent {
+  TurnStart(HeroID),
+  // `counter` if the attack was itself a counter, which can't be countered.
+  Attacked { target: HeroID, by: HeroID, counter: bool },
+  Death { hero: HeroID, by: Option<HeroID> },
+  Debuffed { target: HeroID, by: HeroID },
+  // HP as a percentage of max HP before and after taking damage.
+  HpDrop { hero: HeroID, from: f32, to: f32, by: Option<HeroID> },
+}
+
+impl TriggerEvent {
+  fn by(&self) -> Option<HeroID> {
+    use TriggerEvent::*;
+
+    match *self {
+      TurnStart(_) => None,
+      Attacked { by, .. } | Debuffed { by, .. } => Some(by),
+      Death { by, .. } | HpDrop { by, .. } => by,
+    }
+  }
+}
+
+impl Trigger {
+  // Hero `Targeting::Trigger` aims at when this trigger fires on `event`.
+  fn subject(&self, event: &TriggerEvent) -> Option<HeroID> {
+    match (*self, *event) {
+      (Trigger::Attack, TriggerEvent::Attacked { target, .. }) => Some(target),
+      _ => event.by(),
+    }
+  }
+
+  // Whether `hero` has a passive with this trigger to run for `event`.
+  fn fires(&self, event: &TriggerEvent, hero: HeroID, ss: &BattleSnapshot) -> bool {
+    let me = &ss.heroes[hero];
+    let enemy = |x: HeroID| ss.heroes[x].team != me.team;
+
+    match (*self, *event) {
+      (Trigger::Death, TriggerEvent::Death { hero: x, .. }) => x == hero && !me.alive,
+      _ if !me.alive => false,
+      (Trigger::TurnStart, TriggerEvent::TurnStart(x)) => x == hero,
+      (Trigger::Attacked, TriggerEvent::Attacked { target, by, .. }) => target == hero && enemy(by),
+      (Trigger::Attack, TriggerEvent::Attacked { target, by, .. }) => by == hero && enemy(target),
+      (Trigger::AllyDeath, TriggerEvent::Death { hero: x, .. }) => x != hero && !enemy(x),
+      (Trigger::Debuffed, TriggerEvent::Debuffed { target, by }) => target == hero && enemy(by),
+      (Trigger::HpBelow(pct), TriggerEvent::HpDrop { hero: x, from, to, .. }) => {
+        x == hero && from >= pct && to < pct
+      }
+      _ => false,
+    }
+  }
+}
+
+// What the skill currently being used has done so far. Reset at the start of
+// every skill use so later components can react to earlier ones.
+#[derive(Clone, Default)]
+pub struct SkillUse {
+  pub caster: HeroID,
+  pub hits: Vec<(HeroID, HitKind)>,
+  pub damage: Vec<(HeroID, f32)>,
+  pub kills: Vec<HeroID>,
+  // Who set off the passive being used, see `Targeting::Trigger`.
+  pub trigger: Option<HeroID>,
+  // The skill use is a counter, see `counter_attack`.
+  pub counter: bool,
+  // Single target components can only hit this hero, when alive.
+  pub forced: Option<HeroID>,
+}
+
+impl SkillUse {
+  pub fn new(caster: HeroID) -> Self {
+    SkillUse { caster, ..Default::default() }
+  }
+
+  pub fn crit(&self, target: HeroID) -> bool {
+    self.hits.iter().any(|&(x, hit)| x == target && hit == HitKind::Crit)
+  }
+
+  pub fn crits(&self) -> usize {
+    self.hits.iter().filter(|(_, hit)| *hit == HitKind::Crit).count()
+  }
+
+  pub fn damage_to(&self, target: HeroID) -> f32 {
+    self.damage.iter().filter(|(x, _)| *x == target).map(|(_, dmg)| dmg).sum()
+  }
+
+  pub fn total_damage(&self) -> f32 {
+    self.damage.iter().map(|(_, dmg)| dmg).sum()
+  }
 }
 
 pub type HeroID = usize;
 
+// Readiness of a full CR gauge. Readiness counts hundredths of a percent.
+pub const CR_FULL: u32 = 10000;
+
 impl BattleSnapshot {
-  fn get_turn_hero_id(&self) -> HeroID {
+  pub fn new(heroes: Vec<Hero>) -> Self {
+    BattleSnapshot {
+      heroes,
+      turn: 0,
+      log: CombatLog::new(),
+      skill_use: SkillUse::default(),
+      triggers: vec![],
+      extra_turn: None,
+      acting: None,
+      passives_used: vec![],
+    }
+  }
+
+  pub fn is_over(&self) -> bool {
+    let mut teams = self.heroes.iter().filter(|x| x.alive).map(|x| x.team);
+    match teams.next() {
+      Some(first) => teams.all(|x| x == first),
+      None => true,
+    }
+  }
+
+  // Fill the CR gauge of every living hero at the rate of their SPD until
+  // the first one is full.
+  pub fn advance_readiness(&mut self) {
+    let time_to_full = |x: &Hero| (CR_FULL - u32::min(x.readiness, CR_FULL)) as f32 / x.stats.spd;
+    let next = (0..)
+      .zip(&self.heroes)
+      .filter(|(_, x)| x.alive)
+      .min_by(|(_, x), (_, y)| time_to_full(x).partial_cmp(&time_to_full(y)).unwrap())
+      .map(|(i, x)| (i, time_to_full(x)));
+
+    if let Some((next, time)) = next {
+      for hero in self.heroes.iter_mut().filter(|x| x.alive) {
+        let gain = (hero.stats.spd * time).round() as u32;
+        hero.readiness = u32::min(CR_FULL, hero.readiness + gain);
+      }
+      self.heroes[next].readiness = CR_FULL;
+    }
+  }
+
+  // None once every hero is dead.
+  pub fn get_turn_hero_id(&self) -> Option<HeroID> {
     (0..)
       .zip(&self.heroes)
       .filter(|(_, x)| x.alive)
       .max_by(|(_, x), (_, y)| x.readiness.cmp(&y.readiness))
       .map(|(i, _)| i)
-      .unwrap()
-  }
-
-  fn get_base_stats_for_hero(&self, id: HeroID) -> &Hero {
-    &self.base_stats[id]
   }
 }
 
-enum DamageSource<'a> {
-  Hero(&'a Hero),
-  Effect,
+#[derive(Copy, Clone)]
+enum DamageSource {
+  Hero(HeroID),
+  // Damage over time and who applied it.
+  Effect(Effect, Option<HeroID>),
 }
 
-fn deal_damage(src: DamageSource, target: &mut Hero, dmg: &DamageInstance) -> bool {
+// Returns the damage taken to HP and the damage absorbed by shields, or None
+// if it was blocked entirely.
+fn deal_damage(src: DamageSource, target: &mut Hero, dmg: &DamageInstance) -> Option<(f32, f32)> {
   if target.effects.has_effect(Effect::Invincible) {
-    return false;
+    return None;
   }
 
   if matches!(src, DamageSource::Hero(_)) {
@@ -339,16 +730,99 @@ fn deal_damage(src: DamageSource, target: &mut Hero, dmg: &DamageInstance) -> bo
       }
     });
     if has_skill_null {
-      return false;
+      return None;
     }
   }
 
-  target.stats.hp -= calculate_damage_taken(src, target, dmg);
+  let taken = calculate_damage_taken(src, target, dmg);
+  let absorbed = target.effects.absorb(taken);
+  let mut lost = taken - absorbed;
+  target.stats.hp -= lost;
+  if matches!(src, DamageSource::Hero(_)) {
+    target.wake_up();
+  }
 
-  if target.stats.hp < 0.0 {
+  if target.stats.hp <= 0.0 && target.effects.has_effect(Effect::Immortal) {
+    // What immortality held back was never lost.
+    lost -= 1.0 - target.stats.hp;
+    target.stats.hp = 1.0;
+  } else if target.stats.hp <= 0.0 {
+    // The fallen lose all their effects, so a revive starts clean.
     target.alive = false;
+    target.consume_effects(|entry| entry.duration);
   }
-  true
+  Some((lost, absorbed))
+}
+
+// `deal_damage` on a hero of the snapshot, recording the outcome in the log.
+fn damage_hero(ss: &mut BattleSnapshot, src: DamageSource, target: HeroID, dmg: &DamageInstance) -> Option<f32> {
+  let hp_percent = |x: &Hero| x.stats.hp / x.stats.max_hp * 100.0;
+  let was_alive = ss.heroes[target].alive;
+  let from = hp_percent(&ss.heroes[target]);
+  let (taken, absorbed) = deal_damage(src, &mut ss.heroes[target], dmg)?;
+  if absorbed > 0.0 {
+    ss.log.push(BattleEvent::ShieldAbsorbed { target, amount: absorbed });
+  }
+  let (src, direct) = match src {
+    DamageSource::Hero(id) => {
+      ss.log.push(BattleEvent::Damage { src: Some(id), target, amount: taken });
+      (Some(id), true)
+    }
+    DamageSource::Effect(effect, by) => {
+      ss.log.push(BattleEvent::DotDamage { src: by, target, effect, amount: taken });
+      (by, false)
+    }
+  };
+  ss.triggers.push(TriggerEvent::HpDrop { hero: target, from, to: hp_percent(&ss.heroes[target]), by: src });
+  let killed = was_alive && !ss.heroes[target].alive;
+  if killed {
+    ss.log.push(BattleEvent::Death { hero: target });
+    ss.triggers.push(TriggerEvent::Death { hero: target, by: src });
+  }
+  if direct && src == Some(ss.skill_use.caster) {
+    ss.skill_use.damage.push((target, taken));
+    if killed {
+      ss.skill_use.kills.push(target);
+    }
+  }
+  Some(taken)
+}
+
+// `heal` on a living hero of the snapshot, logging the HP restored and the
+// part of `amount` lost to max HP.
+fn heal_hero(ss: &mut BattleSnapshot, src: Option<HeroID>, target: HeroID, amount: f32) {
+  let hero = &mut ss.heroes[target];
+  let hp = hero.stats.hp;
+  if !hero.alive || amount <= 0.0 || !heal(hero, amount) {
+    return;
+  }
+  let healed = hero.stats.hp - hp;
+  ss.log.push(BattleEvent::Heal { src, target, amount: healed, overheal: amount - healed });
+}
+
+// Move a living hero's CR gauge by `amount` of a full gauge, within empty
+// and full.
+fn change_readiness(ss: &mut BattleSnapshot, target: HeroID, amount: f32) {
+  let hero = &mut ss.heroes[target];
+  if !hero.alive {
+    return;
+  }
+  let readiness = hero.readiness as f32 + amount * CR_FULL as f32;
+  hero.readiness = readiness.round().clamp(0.0, CR_FULL as f32) as u32;
+  ss.log.push(BattleEvent::CrChanged { target, amount });
+}
+
+// Bring a dead hero back with `rate` of max HP. Their CR gauge starts
+// empty, so they rejoin the turn order behind everyone else.
+fn revive_hero(ss: &mut BattleSnapshot, src: HeroID, target: HeroID, rate: f32) {
+  let hero = &mut ss.heroes[target];
+  if hero.alive {
+    return;
+  }
+  hero.alive = true;
+  hero.stats.hp = f32::max(1.0, hero.stats.max_hp * rate);
+  hero.readiness = 0;
+  ss.log.push(BattleEvent::Revived { src, target, hp: hero.stats.hp });
 }
 
 fn heal(target: &mut Hero, amount: f32) -> bool {
@@ -362,149 +836,1262 @@ fn heal(target: &mut Hero, amount: f32) -> bool {
   true
 }
 
-fn calculate_damage_taken(src: DamageSource, target: &Hero, raw_dmg: &DamageInstance) -> f32 {
-  0.0
+// DEF needed to halve incoming damage.
+const DEF_SCALE: f32 = 300.0;
+
+fn calculate_damage_taken(_src: DamageSource, target: &Hero, dmg: &DamageInstance) -> f32 {
+  let def = target.stats.def * (1.0 - dmg.def_pen);
+  dmg.raw_dmg / (1.0 + f32::max(0.0, def) / DEF_SCALE) + dmg.fixed
 }
 
 struct DamageInstance {
   raw_dmg: f32,
   def_pen: f32,
+  // Taken in full whatever the DEF.
+  fixed: f32,
 }
 
-fn hero_on_turn_start(hero: &mut Hero) {
+// Damage multiplier of a missed attack. Misses can't crit.
+const MISS_DMG_RATE: f32 = 0.75;
+
+fn attack<'b>(ss: &BattleSnapshot, src: HeroID, target: HeroID, action: &DamageAction) -> RngNode<'b, BattleSnapshot> {
+  let (caster, victim) = (&ss.heroes[src], &ss.heroes[target]);
+  let attacker = &caster.stats;
+  let defender = &victim.stats;
+
+  let hit_chance = (attacker.hit_chance / 100.0).clamp(0.0, 1.0);
+  let crit_chance = ((attacker.cc - defender.crit_resist) / 100.0).clamp(0.0, 1.0);
+  let base = action.scaling.iter().fold(attacker.atk * action.atk_rate, |acc, x| acc + x.rate * x.of.of(caster, victim));
+  let debuffs = victim.effects.count(Polarity::Debuff) as f32;
+  let raw_dmg = base * action.pow * (1.0 + action.per_debuff * debuffs);
+  let crit_rate = attacker.cdmg / 100.0;
+  let def_pen = action.def_pen;
+  let fixed = action.fixed;
+  let lifesteal = (action.lifesteal + attacker.lifesteal) / 100.0;
+
+  let strike = move |ss: &mut BattleSnapshot, hit: HitKind, chance: f32| {
+    let rate = match hit {
+      HitKind::Normal => 1.0,
+      HitKind::Crit => crit_rate,
+      HitKind::Miss => MISS_DMG_RATE,
+    };
+    ss.log.push(BattleEvent::Attack { src, target, hit, chance });
+    ss.skill_use.hits.push((target, hit));
+    ss.triggers.push(TriggerEvent::Attacked { target, by: src, counter: ss.skill_use.counter });
+    let taken = damage_hero(ss, DamageSource::Hero(src), target, &DamageInstance { raw_dmg: raw_dmg * rate, def_pen, fixed });
+    if let Some(taken) = taken {
+      heal_hero(ss, Some(src), src, taken * lifesteal);
+    }
+    RngNode::End
+  };
+
+  RngNode::branch_two(hit_chance, move |_| {
+    RngNode::branch_two(crit_chance, move |ss| strike(ss, HitKind::Crit, hit_chance * crit_chance))
+      .or(move |ss| strike(ss, HitKind::Normal, hit_chance * (1.0 - crit_chance)))
+  }).or(move |ss| strike(ss, HitKind::Miss, 1.0 - hit_chance))
+}
+
+fn hero_on_turn_start(ss: &mut BattleSnapshot, id: HeroID) {
   // Apply bleeds, continuous healing
   use Effect::*;
 
-  let mut bleed_dmg = Vec::<DamageInstance>::new();
-  let mut auto_heal = 0_f32;
+  let hero = &mut ss.heroes[id];
+
+  let mut dots = Vec::<(Effect, Option<HeroID>, DamageInstance)>::new();
+  let mut heals = Vec::<(Option<HeroID>, f32)>::new();
 
   let hero_hp = hero.stats.max_hp;
   hero.consume_effects(|entry| match entry.effect {
-    Burn(dmg) | Bleed(dmg) => {
-      bleed_dmg.push(DamageInstance {
-        raw_dmg: dmg,
-        def_pen: 0.7,
-      });
+    Burn(_) | Bleed(_) | Poison => {
+      dots.push((entry.effect, entry.source, entry.tick_damage(hero_hp)));
       1
     }
-    Poison => {
-      bleed_dmg.push(DamageInstance {
-        raw_dmg: hero_hp * 0.05,
-        def_pen: 1.0,
-      });
+    ContinuousHealing => {
+      heals.push((entry.source, hero_hp * 0.15));
       1
     }
-    ContinuousHealing => {
-      auto_heal += hero_hp * 0.15;
+    Regen(DotAmount(amount)) => {
+      heals.push((entry.source, amount));
       1
     }
     _ => 0,
   });
 
-  let mut total_dmg = 0_f32;
-  for dmg in &bleed_dmg {
-    total_dmg += calculate_damage_taken(DamageSource::Effect, hero, dmg);
+  for (by, amount) in heals {
+    heal_hero(ss, by, id, amount);
   }
-  if total_dmg > auto_heal {
-    deal_damage(
-      DamageSource::Effect,
-      hero,
-      &DamageInstance {
-        raw_dmg: total_dmg - auto_heal,
-        def_pen: 1.0,
-      },
-    );
-  } else {
-    heal(hero, auto_heal - total_dmg);
+  // Each stack ticks separately so its damage is credited to whoever put it there.
+  for (effect, by, dmg) in &dots {
+    if ss.heroes[id].alive {
+      damage_hero(ss, DamageSource::Effect(*effect, *by), id, dmg);
+    }
   }
 }
 
-fn hero_on_turn_end(hero: &Hero) {}
+fn hero_on_turn_end(ss: &mut BattleSnapshot, id: HeroID) {
+  ss.heroes[id].tick_effects();
+  ss.acting = None;
+}
 
 enum EffectSource {
   Enemy(HeroID),
-  SelfTargeted,
+  // Self or an ally of `HeroID`: lands with the skill's chance, unresisted.
+  Ally(HeroID),
 }
 
+// Floor of the chance to resist a debuff, however high the attacker's EFF.
+// An enemy effect is resisted (effres - eff)% of the time, at least this
+// often, and otherwise lands with the skill's chance.
+const MIN_RESIST: f32 = 0.15;
+
 fn apply_effect<'a, 'b>(
   snapshot: &'a BattleSnapshot,
   src: EffectSource,
   target: HeroID,
-  effect: Effect,
+  effect: SkillEffect,
   duration: u32,
   chance: f32,
 ) -> RngNode<'b, BattleSnapshot> {
-  let effect_proc = move |ss: &mut BattleSnapshot| {
-    let target = &mut ss.heroes[target];
-    target.apply_effect(effect, duration);
+  let (by, enemy) = match src {
+    EffectSource::Enemy(x) => (x, true),
+    EffectSource::Ally(x) => (x, false),
+  };
+  // Fixed at the ATK the applier has now.
+  let effect = effect.resolve(snapshot.heroes[by].stats.atk);
+  let effect_proc = move |ss: &mut BattleSnapshot, chance: f32| {
+    // Effects don't wear off at the end of the turn they were applied on.
+    let grace = (ss.acting == Some(target)) as u32;
+    if !ss.heroes[target].apply_effect(effect, duration + grace, Some(by)) {
+      ss.log.push(BattleEvent::EffectBlocked { target, effect });
+      return RngNode::End
+    }
+    ss.log.push(BattleEvent::EffectApplied { target, effect, duration, chance });
+    if enemy && effect.is_debuff() {
+      ss.triggers.push(TriggerEvent::Debuffed { target, by });
+    }
     RngNode::End
   };
-  match src {
-    EffectSource::SelfTargeted => RngNode::always(effect_proc),
+  // Allies never resist.
+  let resisted = match src {
+    EffectSource::Ally(_) => 0.0,
     EffectSource::Enemy(enemy) => {
-      let enemy = &snapshot.heroes[enemy];
-      let _target = &snapshot.heroes[target];
-      let eff = enemy.stats.eff;
-      let resist = _target.stats.effres;
-      let chance = f32::min(0.85, chance * f32::max(0.0, eff - resist));
-      RngNode::branch_two(chance, effect_proc).or(|_| RngNode::End)
+      let eff = snapshot.heroes[enemy].stats.eff;
+      let resist = snapshot.heroes[target].stats.effres;
+      ((resist - eff) / 100.0).clamp(MIN_RESIST, 1.0)
     }
+  };
+  // The skill's chance is rolled first, and only a proc can be resisted.
+  RngNode::branch_two(chance, move |_| {
+    RngNode::branch_two(1.0 - resisted, move |ss| effect_proc(ss, chance * (1.0 - resisted))).or(move |ss| {
+      ss.log.push(BattleEvent::EffectResisted { target, effect, chance: resisted });
+      RngNode::End
+    })
+  }).or(move |ss| {
+    ss.log.push(BattleEvent::EffectMissed { target, effect, chance: 1.0 - chance });
+    RngNode::End
+  })
+}
+
+fn remove_effects<'b>(ss: &mut BattleSnapshot, target: HeroID, polarity: Polarity, count: u32) -> RngNode<'b, BattleSnapshot> {
+  for effect in ss.heroes[target].dispel(polarity, count) {
+    ss.log.push(BattleEvent::EffectRemoved { target, effect });
+  }
+  RngNode::End
+}
+
+// All remaining turns of the target's `dot` stacks at once, as one hit from
+// `src`.
+fn detonate<'b>(ss: &mut BattleSnapshot, src: HeroID, target: HeroID, dot: DotKind) -> RngNode<'b, BattleSnapshot> {
+  let hero = &ss.heroes[target];
+  let (stacks, total) = hero.effects.get_bleed_effects()
+    .filter(|x| dot.matches(x.effect))
+    .fold((0, 0.0), |(stacks, total), x| {
+      let tick = calculate_damage_taken(DamageSource::Hero(src), hero, &x.tick_damage(hero.stats.max_hp));
+      (stacks + 1, total + tick * x.duration as f32)
+    });
+  if stacks == 0 {
+    return RngNode::End
   }
+
+  ss.heroes[target].consume_effects(|x| if dot.matches(x.effect) { x.duration } else { 0 });
+  ss.log.push(BattleEvent::Detonated { src, target, stacks });
+  // DEF was already taken into account per stack.
+  damage_hero(ss, DamageSource::Hero(src), target, &DamageInstance { raw_dmg: total, def_pen: 1.0, fixed: 0.0 });
+  RngNode::End
 }
 
 pub trait SkillPicker: Sync {
-  fn pick_skill(&self, snapshot: &BattleSnapshot, hero: HeroID) -> &Skill;
+  fn skills(&self, hero: HeroID) -> &[Skill];
+  // Slot in `skills(hero)` of the skill to use this turn.
+  fn pick_skill(&self, snapshot: &BattleSnapshot, hero: HeroID) -> usize;
+
+  fn passives(&self, _hero: HeroID) -> &[Passive] {
+    &[]
+  }
 }
 
-fn use_skill<'a, 'b>(skill: &'a Skill, src: HeroID) -> RngNode<'b, BattleSnapshot> where 'a: 'b {
-  use crate::skill::SkillAction::*;
+pub fn use_skill<'a, 'b>(skill: &'a Skill, src: HeroID) -> RngNode<'b, BattleSnapshot> where 'a: 'b {
+  use_components(&skill.components, SkillUse::new(src))
+}
 
-  RngNode::for_each(&skill.components, move |ss, comp| {
-    match comp.activate_condition {
-      Some(cond) if !cond(ss, &ss.heroes[src], &ss.heroes[src]) => return RngNode::End,
-      _ => ()
-    }
+fn use_components<'a, 'b>(components: &'a Vec<SkillComponent>, skill_use: SkillUse) -> RngNode<'b, BattleSnapshot> where 'a: 'b {
+  use crate::skill::SkillAction::*;
 
-    RngNode::for_each(&comp.targeting.get_target(ss, src), move |ss, target| {
-      match comp.action {
-        Effect { effect, chance, duration } => {
-          apply_effect(ss, EffectSource::Enemy(src), target, effect, duration, chance)
+  let src = skill_use.caster;
+  RngNode::always(move |ss: &mut BattleSnapshot| {
+    ss.skill_use = skill_use;
+
+    RngNode::for_each(components, move |ss: &mut BattleSnapshot, comp: &SkillComponent| {
+      let targets: Vec<HeroID> = comp.targeting.get_target(ss, src).into_iter().filter(|&target| {
+        comp.activate_condition.as_ref().is_none_or(|cond| cond.eval(&ConditionContext {
+          caster: &ss.heroes[src],
+          target: &ss.heroes[target],
+          target_id: target,
+          skill_use: &ss.skill_use,
+        }))
+      }).collect();
+
+      RngNode::for_each(&targets, move |ss: &mut BattleSnapshot, target: HeroID| {
+        match comp.action {
+          Effect { effect, chance, duration } => {
+            let src = if ss.heroes[src].team == ss.heroes[target].team {
+              EffectSource::Ally(src)
+            } else {
+              EffectSource::Enemy(src)
+            };
+            apply_effect(ss, src, target, effect, duration, chance)
+          }
+          Damage(ref action) => attack(ss, src, target, action),
+          ExtraTurn => {
+            ss.extra_turn = Some(src);
+            RngNode::End
+          }
+          DispelBuffs { count } => remove_effects(ss, target, Polarity::Buff, count),
+          Detonate { dot } => detonate(ss, src, target, dot),
+          Heal { rate, scaling } => {
+            heal_hero(ss, Some(src), target, rate * scaling.of(&ss.heroes[src], &ss.heroes[target]));
+            RngNode::End
+          }
+          Shield { rate, scaling, duration } => {
+            let pool = rate * scaling.of(&ss.heroes[src], &ss.heroes[target]);
+            apply_effect(ss, EffectSource::Ally(src), target, self::Effect::Shield(pool).into(), duration, 1.0)
+          }
+          CleanseDebuffs { count } => remove_effects(ss, target, Polarity::Debuff, count),
+          Revive { rate } => {
+            revive_hero(ss, src, target, rate);
+            RngNode::End
+          }
+          ChangeCr { amount } => {
+            change_readiness(ss, target, amount);
+            RngNode::End
+          }
+          Splash { atk_rate, def_pen } => {
+            let raw_dmg = ss.heroes[src].stats.atk * atk_rate;
+            damage_hero(ss, DamageSource::Hero(src), target, &DamageInstance { raw_dmg, def_pen, fixed: 0.0 });
+            RngNode::End
+          }
         }
-        _ => panic!("Not implemented"),
-      }
+      })
     })
-  }).set_label(format!("Skill used"))
+  })
 }
 
-pub fn turn_start(picker: &'static impl SkillPicker) -> RngNode<BattleSnapshot> {
+pub fn turn_start(picker: &'static impl SkillPicker) -> RngNode<'static, BattleSnapshot> {
     RngNode::always(move |ss: &mut BattleSnapshot| {
-      if !ss.heroes.iter().any(|x| x.alive) {
+      if ss.is_over() {
         return RngNode::End
       }
 
-      let hero = ss.get_turn_hero_id();
-      hero_on_turn_start(&mut ss.heroes[hero]);
+      ss.advance_readiness();
+      match ss.get_turn_hero_id() {
+        Some(hero) => take_turn(picker, hero),
+        None => RngNode::End,
+      }
+    })
+}
+
+// One turn of `hero`, whether earned through CR or as an extra turn.
+fn take_turn(picker: &'static impl SkillPicker, hero: HeroID) -> RngNode<'static, BattleSnapshot> {
+    RngNode::always(move |ss: &mut BattleSnapshot| {
+      ss.heroes[hero].readiness = 0;
+      ss.turn += 1;
+      ss.acting = Some(hero);
+      ss.log.push(BattleEvent::TurnStart { turn: ss.turn, hero });
+      ss.triggers.push(TriggerEvent::TurnStart(hero));
+      hero_on_turn_start(ss, hero);
+
+      let turn_end = move |ss: &mut BattleSnapshot| {
+        hero_on_turn_end(ss, hero);
+        RngNode::End
+      };
+      react(picker, 0).then(move |ss| {
+        if !ss.heroes[hero].alive || ss.is_over() {
+          // Nothing else ends this turn, so effects applied later don't get its grace.
+          ss.acting = None;
+          return RngNode::End
+        }
+        ss.heroes[hero].tick_cooldowns();
+        if !ss.heroes[hero].can_act() {
+          ss.log.push(BattleEvent::TurnSkipped { hero });
+          return turn_end(ss)
+        }
+        let (restricted, forced) = ss.heroes[hero].restricted();
+        let slot = if restricted { 0 } else { picker.pick_skill(ss, hero) };
+        let skill = &picker.skills(hero)[slot];
+        ss.heroes[hero].put_on_cooldown(slot, skill.cooldown);
+        ss.log.push(BattleEvent::SkillUsed { hero, skill: skill.name.clone() });
+
+        use_components(&skill.components, SkillUse { forced, ..SkillUse::new(hero) })
+          .then(move |_| react(picker, 0))
+          .then(move |ss| match skill.dual_atk {
+            Some(ref dual) => dual_attack(picker, ss, hero, dual),
+            None => RngNode::End,
+          })
+          .then(turn_end)
+      })
+    })
+}
 
-      let skill = picker.pick_skill(ss, hero);
+// Reactions to reactions stop at this depth so two passives can't keep
+// setting each other off.
+const MAX_REACTION_DEPTH: u32 = 3;
+
+// Run the passives set off by pending trigger events, in event order. Events
+// raised by those passives are reacted to in turn, up to `MAX_REACTION_DEPTH`.
+fn react(picker: &'static impl SkillPicker, depth: u32) -> RngNode<'static, BattleSnapshot> {
+  RngNode::always(move |ss: &mut BattleSnapshot| {
+    let events = std::mem::take(&mut ss.triggers);
+    if depth >= MAX_REACTION_DEPTH {
+      return RngNode::End
+    }
 
-      use_skill(skill, hero)
+    let mut node = RngNode::End;
+    let mut countered = vec![];
+    for event in events {
+      // Once per attacker and skill use, however many components hit.
+      if let TriggerEvent::Attacked { target, by, counter: false } = event {
+        if !countered.contains(&(target, by)) {
+          countered.push((target, by));
+          node = node.then(move |ss| counter_attack(picker, ss, target, by));
+        }
+      }
+      for hero in 0..ss.heroes.len() {
+        for (slot, passive) in picker.passives(hero).iter().enumerate() {
+          if !passive.trigger.fires(&event, hero, ss) {
+            continue;
+          }
+          node = node.then(move |ss: &mut BattleSnapshot| {
+            // Earlier reactions may have changed things. A death passive
+            // can still bring the last of a team back.
+            let ready = match passive.trigger {
+              Trigger::Death => !ss.heroes[hero].alive,
+              _ => ss.heroes[hero].alive && !ss.is_over(),
+            };
+            if !ready || ss.passives_used.contains(&(hero, slot)) {
+              return RngNode::End
+            }
+            RngNode::branch_two(passive.chance, move |ss: &mut BattleSnapshot| {
+              if passive.once {
+                ss.passives_used.push((hero, slot));
+              }
+              ss.log.push(BattleEvent::PassiveTriggered { hero, passive: passive.name.clone() });
+              use_components(&passive.components, SkillUse { trigger: passive.trigger.subject(&event), ..SkillUse::new(hero) })
+            }).or(|_| RngNode::End)
+          });
+        }
+      }
+    }
+    node.then(move |ss| {
+      if ss.triggers.is_empty() {
+        RngNode::End
+      } else {
+        react(picker, depth + 1)
+      }
     })
+  })
 }
 
-struct AISkillPicker {
+// With the hero's counter chance, `hero` answers `attacker` with their first
+// skill. Single target components are aimed back at the attacker.
+fn counter_attack(picker: &'static impl SkillPicker, ss: &BattleSnapshot, hero: HeroID, attacker: HeroID) -> RngNode<'static, BattleSnapshot> {
+  let me = &ss.heroes[hero];
+  // Teammates hit by friendly skills don't strike back.
+  if me.team == ss.heroes[attacker].team {
+    return RngNode::End
+  }
+  if !me.alive || !me.can_act() || !ss.heroes[attacker].alive || picker.skills(hero).is_empty() {
+    return RngNode::End
+  }
+  let buff = if me.effects.has_effect(Effect::Counter) { 100.0 } else { 0.0 };
+  let chance = ((me.stats.counter + buff) / 100.0).clamp(0.0, 1.0);
+
+  RngNode::branch_two(chance, move |ss: &mut BattleSnapshot| {
+    let skill = &picker.skills(hero)[0];
+    ss.log.push(BattleEvent::Counter { hero, target: attacker });
+    ss.log.push(BattleEvent::SkillUsed { hero, skill: skill.name.clone() });
+    let skill_use = SkillUse { trigger: Some(attacker), counter: true, forced: Some(attacker), ..SkillUse::new(hero) };
+    use_components(&skill.components, skill_use)
+  }).or(|_| RngNode::End)
+}
+
+// With `dual.chance`, an ally of `src` follows up with their first skill.
+fn dual_attack(picker: &'static impl SkillPicker, ss: &BattleSnapshot, src: HeroID, dual: &DualAtk) -> RngNode<'static, BattleSnapshot> {
+  let team = ss.heroes[src].team;
+  let allies: Vec<HeroID> = (0..)
+    .zip(&ss.heroes)
+    .filter(|&(i, x)| i != src && x.alive && x.can_act() && x.team == team && !picker.skills(i).is_empty())
+    .map(|(i, _)| i)
+    .collect();
+
+  let follow_up = move |ss: &mut BattleSnapshot, ally: HeroID| {
+    let skill = &picker.skills(ally)[0];
+    ss.log.push(BattleEvent::DualAttack { hero: ally, with: src });
+    ss.log.push(BattleEvent::SkillUsed { hero: ally, skill: skill.name.clone() });
+    use_skill(skill, ally).then(move |_| react(picker, 0))
+  };
 
+  let pick_ally = match dual.target {
+    _ if allies.is_empty() => return RngNode::End,
+    DualAtkTarget::HighestAtk => {
+      let ally = *allies.iter()
+        .max_by(|&&x, &&y| ss.heroes[x].stats.atk.partial_cmp(&ss.heroes[y].stats.atk).unwrap())
+        .unwrap();
+      RngNode::always(move |ss| follow_up(ss, ally))
+    }
+    DualAtkTarget::Random => pick_uniform(allies, follow_up),
+  };
+  RngNode::branch_two(dual.chance, move |_| pick_ally).or(|_| RngNode::End)
 }
 
-// impl SkillPicker for AISkillPicker {
-//   fn pick_skill(&self, snapshot: &BattleSnapshot, hero: HeroID) -> &Skill {
-//     let hero = &snapshot.heroes[hero];
+// Equal chance branch for each of `ids`, built from two way branches.
+fn pick_uniform<F>(mut ids: Vec<HeroID>, f: F) -> RngNode<'static, BattleSnapshot>
+  where F: FnOnce(&mut BattleSnapshot, HeroID) -> RngNode<'static, BattleSnapshot> + Send + Copy + 'static
+{
+  let first = ids.remove(0);
+  if ids.is_empty() {
+    return RngNode::always(move |ss| f(ss, first));
+  }
+  let chance = 1.0 / (ids.len() + 1) as f32;
+  RngNode::branch_two(chance, move |ss| f(ss, first)).or(move |_| pick_uniform(ids, f))
+}
+
+// Take turns until one team is wiped out or `max_turns` turns have passed.
+pub fn battle(picker: &'static impl SkillPicker, max_turns: u32) -> RngNode<'static, BattleSnapshot> {
+  RngNode::always(move |ss: &mut BattleSnapshot| {
+    if ss.is_over() || ss.turn >= max_turns {
+      return RngNode::End
+    }
+    // An extra turn skips the CR race, so everyone else's gauge stays put.
+    let next = match ss.extra_turn.take() {
+      Some(hero) if ss.heroes[hero].alive => {
+        ss.log.push(BattleEvent::ExtraTurn { hero });
+        take_turn(picker, hero)
+      }
+      _ => turn_start(picker),
+    };
+    next.then(move |_| battle(picker, max_turns))
+  })
+}
+
+// Skills of every hero, indexed by HeroID. Every hero needs at least one.
+pub struct AISkillPicker {
+  skills: Vec<Vec<Skill>>,
+  // Heroes' own passives and artifact procs, kept apart so either can be
+  // set first, and `passives` with both that triggers go through.
+  own_passives: Vec<Vec<Passive>>,
+  procs: Vec<Vec<Passive>>,
+  passives: Vec<Vec<Passive>>,
+}
+
+impl AISkillPicker {
+  pub fn new(skills: Vec<Vec<Skill>>) -> Self {
+    AISkillPicker { skills, own_passives: vec![], procs: vec![], passives: vec![] }
+  }
 
+  // Passives of every hero, indexed by HeroID. Heroes past the end have none.
+  pub fn with_passives(mut self, passives: Vec<Vec<Passive>>) -> Self {
+    self.own_passives = passives;
+    self.combine_passives();
+    self
+  }
 
-//   }
-// }
+  // Procs of the artifact `hero` holds, run after their own passives.
+  pub fn with_artifact(mut self, hero: HeroID, artifact: &Artifact) -> Self {
+    if self.procs.len() <= hero {
+      self.procs.resize(hero + 1, vec![]);
+    }
+    self.procs[hero] = artifact.procs.clone();
+    self.combine_passives();
+    self
+  }
 
-enum BattleResult {
+  fn combine_passives(&mut self) {
+    let heroes = self.own_passives.len().max(self.procs.len());
+    let of = |table: &[Vec<Passive>], hero: usize| table.get(hero).cloned().unwrap_or_default();
+    self.passives = (0..heroes).map(|x| [of(&self.own_passives, x), of(&self.procs, x)].concat()).collect();
+  }
+}
+
+// Uses the last skill that is off cooldown, which for most heroes is the
+// strongest one.
+impl SkillPicker for AISkillPicker {
+  fn skills(&self, hero: HeroID) -> &[Skill] {
+    &self.skills[hero]
+  }
+
+  fn pick_skill(&self, snapshot: &BattleSnapshot, hero: HeroID) -> usize {
+    let hero_state = &snapshot.heroes[hero];
+    (0..self.skills[hero].len()).rev().find(|&slot| hero_state.skill_ready(slot)).unwrap_or(0)
+  }
+
+  fn passives(&self, hero: HeroID) -> &[Passive] {
+    self.passives.get(hero).map_or(&[], |x| x.as_slice())
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BattleResult {
   Win,
   Draw,
   Lose
-}
\ No newline at end of file
+}
+
+impl BattleSnapshot {
+  fn team_alive(&self, team: u32) -> bool {
+    self.heroes.iter().any(|x| x.team == team && x.alive)
+  }
+
+  // Result from the point of view of `team`. A battle cut short with both
+  // sides still standing counts as a draw.
+  pub fn result(&self, team: u32) -> BattleResult {
+    let enemy_alive = self.heroes.iter().any(|x| x.team != team && x.alive);
+    match (self.team_alive(team), enemy_alive) {
+      (true, false) => BattleResult::Win,
+      (false, true) => BattleResult::Lose,
+      _ => BattleResult::Draw,
+    }
+  }
+}
+
+// Stats for tests to adjust: 100k HP, 1000 ATK and 100 SPD, no DEF or crits.
+#[cfg(test)]
+pub fn test_stats() -> HeroStats {
+  HeroStats {
+    max_hp: 100000.0,
+    hp: 100000.0,
+    atk: 1000.0,
+    spd: 100.0,
+    def: 0.0,
+    cc: 0.0,
+    cdmg: 150.0,
+    eff: 0.0,
+    effres: 0.0,
+    element: Element::Dark,
+    hit_chance: 100.0,
+    crit_resist: 0.0,
+    counter: 0.0,
+    lifesteal: 0.0,
+  }
+}
+
+#[cfg(test)]
+pub fn test_hero(team: u32) -> Hero {
+  Hero {
+    name: format!("Team {}", team),
+    stats: test_stats(),
+    base_stats: test_stats(),
+    alive: true,
+    readiness: 0,
+    effects: Effects::new(),
+    team,
+    cooldowns: vec![],
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hero(team: u32) -> Hero {
+    let mut hero = test_hero(team);
+    hero.stats.spd = if team == 1 { 200.0 } else { 100.0 };
+    hero
+  }
+
+  #[test]
+  fn test_reactions_are_bounded() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage());
+    let mut counter = Passive::new("Counter", Trigger::Attacked);
+    counter.new_component(SkillAction::damage()).targeting(Targeting::Trigger);
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]])
+      .with_passives(vec![vec![counter.clone()], vec![counter]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut leaves = vec![];
+    let ss = BattleSnapshot::new(vec![hero(1), hero(2)]);
+    turn_start(picker).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    assert_eq!(leaves.len(), 1);
+
+    // Each counter provokes the next one until the depth limit.
+    let reactors: Vec<HeroID> = leaves[0].0.log.events().iter().filter_map(|x| match *x {
+      BattleEvent::PassiveTriggered { hero, .. } => Some(hero),
+      _ => None,
+    }).collect();
+    assert_eq!(reactors, vec![1, 0, 1]);
+    // Hero 1 takes the skill and one counter, hero 0 two counters.
+    assert_eq!(leaves[0].0.heroes[1].stats.hp, 100000.0 - 2000.0);
+    assert_eq!(leaves[0].0.heroes[0].stats.hp, 100000.0 - 2000.0);
+  }
+
+  #[test]
+  fn test_counters_do_not_chain() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage());
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let (mut attacker, mut defender) = (hero(1), hero(2));
+    attacker.stats.counter = 100.0;
+    defender.stats.counter = 30.0;
+    let ss = BattleSnapshot::new(vec![attacker, defender]);
+
+    let mut leaves = vec![];
+    turn_start(picker).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let counters = |ss: &BattleSnapshot| ss.log.events().iter()
+      .filter(|x| matches!(x, BattleEvent::Counter { .. }))
+      .count();
+
+    assert_eq!(leaves.len(), 2);
+    assert_eq!(leaves[0].1, 0.3);
+    assert_eq!(counters(&leaves[0].0), 1);
+    assert_eq!(leaves[0].0.heroes[0].stats.hp, 100000.0 - 1000.0);
+    assert_eq!(counters(&leaves[1].0), 0);
+  }
+
+  #[test]
+  fn test_no_counter_against_ally() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage());
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut ally = hero(1);
+    ally.stats.counter = 100.0;
+    let ss = BattleSnapshot::new(vec![hero(1), ally]);
+    let mut leaves = vec![];
+    counter_attack(picker, &ss, 1, 0).expand(ss.clone(), &mut |ss, _| leaves.push(ss));
+    assert!(!leaves[0].log.events().iter().any(|x| matches!(x, BattleEvent::Counter { .. })));
+  }
+
+  #[test]
+  fn test_extra_turn_on_kill() {
+    use crate::condition::Condition;
+
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage());
+    skill.new_component(SkillAction::ExtraTurn)
+      .targeting(Targeting::SelfSingle)
+      .activate_condition(Condition::parse("skill.kill").unwrap());
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()]; 3]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut weak = hero(2);
+    weak.stats.hp = 500.0;
+    let ss = BattleSnapshot::new(vec![hero(1), weak, hero(2)]);
+
+    let mut leaves = vec![];
+    battle(picker, 2).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let ss = &leaves[0].0;
+
+    let turns: Vec<HeroID> = ss.log.events().iter().filter_map(|x| match *x {
+      BattleEvent::TurnStart { hero, .. } => Some(hero),
+      _ => None,
+    }).collect();
+    assert_eq!(turns, vec![0, 0]);
+    assert!(ss.log.events().contains(&BattleEvent::ExtraTurn { hero: 0 }));
+    // Half a gauge from the first turn, nothing from the extra one.
+    assert_eq!(ss.heroes[2].readiness, CR_FULL / 2);
+  }
+
+  #[test]
+  fn test_cooldown_skips_skill_until_ready() {
+    let mut s1 = Skill::new("S1");
+    s1.new_component(SkillAction::damage());
+    let mut s3 = Skill::new("S3").cooldown(2);
+    s3.new_component(SkillAction::damage()).action(SkillAction::damage().atk_rate(2.0));
+
+    let picker = AISkillPicker::new(vec![vec![s1.clone(), s3], vec![s1]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+    let ss = BattleSnapshot::new(vec![hero(1), hero(2)]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let mut ss = leaves.swap_remove(0).0;
+    assert_eq!(ss.heroes[0].cooldowns, vec![0, 2]);
+    assert_eq!(ss.heroes[1].stats.hp, 100000.0 - 2000.0);
+
+    // Ticks down at the start of each own turn; S1 stands in while S3 recovers.
+    let turns = take_turn(picker, 0).then(move |_| take_turn(picker, 0));
+    let mut leaves = vec![];
+    turns.expand(ss.clone(), &mut |ss, p| leaves.push((ss, p)));
+    ss = leaves.swap_remove(0).0;
+    let used: Vec<&str> = ss.log.events().iter().filter_map(|x| match x {
+      BattleEvent::SkillUsed { skill, .. } => Some(skill.as_str()),
+      _ => None,
+    }).collect();
+    assert_eq!(used, vec!["S3", "S1", "S3"]);
+    assert_eq!(ss.heroes[0].cooldowns, vec![0, 2]);
+  }
+
+  #[test]
+  fn test_dot_death_ends_turn() {
+    let mut s1 = Skill::new("S1");
+    s1.new_component(SkillAction::damage());
+    let picker = AISkillPicker::new(vec![vec![s1.clone()], vec![s1.clone()], vec![s1]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+    let mut ss = BattleSnapshot::new(vec![hero(1), hero(1), hero(2)]);
+    ss.heroes[0].apply_effect(Effect::Bleed(DotAmount(1e9)), 2, Some(2));
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let ss = leaves.swap_remove(0).0;
+    assert!(!ss.heroes[0].alive);
+    assert_eq!(ss.acting, None);
+  }
+
+  #[test]
+  fn test_dual_attack_with_highest_atk_ally() {
+    let mut s1 = Skill::new("S1");
+    s1.new_component(SkillAction::damage());
+    let mut dual = Skill::new("S1").dual_attack(0.3, DualAtkTarget::HighestAtk);
+    dual.new_component(SkillAction::damage());
+
+    let picker = AISkillPicker::new(vec![vec![dual], vec![s1.clone()], vec![s1.clone()], vec![s1]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+    let (mut weak, mut strong) = (hero(1), hero(1));
+    weak.stats.atk = 500.0;
+    strong.stats.atk = 2000.0;
+    let ss = BattleSnapshot::new(vec![hero(1), weak, strong, hero(2)]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    assert_eq!(leaves.len(), 2);
+    let (procced, p) = &leaves[0];
+    assert_eq!(*p, 0.3);
+    assert!(procced.log.events().contains(&BattleEvent::DualAttack { hero: 2, with: 0 }));
+    assert_eq!(procced.heroes[3].stats.hp, 100000.0 - 1000.0 - 2000.0);
+    assert_eq!(leaves[1].1, 0.7);
+    assert_eq!(leaves[1].0.heroes[3].stats.hp, 100000.0 - 1000.0);
+  }
+
+  #[test]
+  fn test_stun_skips_a_turn() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::effect(Effect::Stun, 1.0, 1));
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut slow = hero(2);
+    slow.stats.spd = 150.0;
+    let ss = BattleSnapshot::new(vec![hero(1), slow]);
+
+    let mut leaves = vec![];
+    battle(picker, 2).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+
+    // Lands unless resisted, at least 15% of the time. Otherwise hero 1
+    // gets to try stunning back.
+    assert_eq!(leaves.len(), 3);
+    let (stunned, p) = &leaves[0];
+    assert_eq!(*p, 0.85);
+    assert!(stunned.log.events().contains(&BattleEvent::TurnSkipped { hero: 1 }));
+    assert!(!stunned.heroes[1].effects.has_effect(Effect::Stun));
+    assert!(!leaves[1].0.log.events().contains(&BattleEvent::TurnSkipped { hero: 1 }));
+  }
+
+  #[test]
+  fn test_debuff_resist_chance() {
+    // Chance a debuff with skill `chance` from a hero with `eff` lands on one
+    // with `effres`.
+    let landed = |eff: f32, effres: f32, chance: f32, src: fn(HeroID) -> EffectSource| {
+      let (mut caster, mut target) = (hero(1), hero(2));
+      caster.stats.eff = eff;
+      target.stats.effres = effres;
+      let ss = BattleSnapshot::new(vec![caster, target]);
+      let mut p = 0.0;
+      apply_effect(&ss, src(0), 1, Effect::AtkDown.into(), 1, chance).expand(ss.clone(), &mut |ss, x| {
+        if ss.heroes[1].effects.has_effect(Effect::AtkDown) {
+          p += x;
+        }
+      });
+      p
+    };
+
+    // Resisted (effres - eff)% of the time, but never less than 15%.
+    assert_eq!(landed(0.0, 0.0, 1.0, EffectSource::Enemy), 0.85);
+    assert_eq!(landed(200.0, 0.0, 1.0, EffectSource::Enemy), 0.85);
+    assert_eq!(landed(0.0, 50.0, 1.0, EffectSource::Enemy), 0.5);
+    assert_eq!(landed(50.0, 100.0, 0.5, EffectSource::Enemy), 0.25);
+    assert_eq!(landed(0.0, 150.0, 1.0, EffectSource::Enemy), 0.0);
+    // Allies only roll the skill's own chance.
+    assert_eq!(landed(0.0, 150.0, 0.5, EffectSource::Ally), 0.5);
+  }
+
+  #[test]
+  fn test_missed_effect_is_not_resisted() {
+    let ss = BattleSnapshot::new(vec![hero(1), hero(2)]);
+    let (mut missed, mut resisted) = (0.0, 0.0);
+    apply_effect(&ss, EffectSource::Enemy(0), 1, Effect::AtkDown.into(), 1, 0.5).expand(ss.clone(), &mut |ss, x| {
+      match ss.log.events().last() {
+        Some(BattleEvent::EffectMissed { chance, .. }) => { missed += x; assert_eq!(*chance, 0.5) }
+        Some(BattleEvent::EffectResisted { chance, .. }) => { resisted += x; assert_eq!(*chance, MIN_RESIST) }
+        _ => (),
+      }
+    });
+    assert_eq!(missed, 0.5);
+    assert!((resisted - 0.5 * MIN_RESIST).abs() < 1e-6);
+  }
+
+  #[test]
+  fn test_effects_wear_off_at_holder_turn_end() {
+    let mut ss = BattleSnapshot::new(vec![hero(1), hero(2)]);
+    ss.heroes[1].apply_effect(Effect::AtkDown, 1, Some(0));
+    ss.heroes[1].apply_effect(Effect::Bleed(DotAmount(100.0)), 1, Some(0));
+    hero_on_turn_end(&mut ss, 0);
+    assert!(ss.heroes[1].effects.has_effect(Effect::AtkDown));
+
+    // Only the holder's own turn counts, and DoTs tick at its start instead.
+    hero_on_turn_end(&mut ss, 1);
+    assert_eq!(ss.heroes[1].effects.entries().collect::<Vec<_>>(), vec![(Effect::Bleed(DotAmount(100.0)), 1)]);
+
+    // Applied during the holder's turn, a one turn effect outlasts that turn.
+    ss.acting = Some(1);
+    let mut leaves = vec![];
+    apply_effect(&ss, EffectSource::Ally(1), 1, Effect::AtkBuff.into(), 1, 1.0).expand(ss.clone(), &mut |ss, _| leaves.push(ss));
+    let mut ss = leaves.swap_remove(0);
+    hero_on_turn_end(&mut ss, 1);
+    assert!(ss.heroes[1].effects.has_effect(Effect::AtkBuff));
+    hero_on_turn_end(&mut ss, 1);
+    assert!(!ss.heroes[1].effects.has_effect(Effect::AtkBuff));
+  }
+
+  #[test]
+  fn test_consume_more_turns_than_left() {
+    let mut effects = Effects::new();
+    effects.apply(Effect::AtkDown, 0, None);
+    effects.apply(Effect::DefBreak, 1, None);
+    assert_eq!(effects.consume_effects(|_| 2).len(), 2);
+    assert_eq!(effects.entries().count(), 0);
+  }
+
+  #[test]
+  fn test_blocking_and_waking() {
+    let mut target = hero(2);
+    target.apply_effect(Effect::Immunity, 2, None);
+    assert!(!target.apply_effect(Effect::Stun, 1, Some(0)));
+    assert!(target.apply_effect(Effect::AtkBuff, 1, None));
+
+    let mut target = hero(2);
+    target.apply_effect(Effect::CannotBuff, 2, Some(0));
+    assert!(!target.apply_effect(Effect::DefBuff, 1, None));
+
+    let mut sleeper = hero(2);
+    sleeper.apply_effect(Effect::Sleep, 2, Some(0));
+    assert!(!sleeper.can_act());
+    deal_damage(DamageSource::Effect(Effect::Poison, None), &mut sleeper, &DamageInstance { raw_dmg: 100.0, def_pen: 0.0, fixed: 0.0 });
+    assert!(!sleeper.can_act());
+    deal_damage(DamageSource::Hero(0), &mut sleeper, &DamageInstance { raw_dmg: 100.0, def_pen: 0.0, fixed: 0.0 });
+    assert!(sleeper.can_act());
+  }
+
+  #[test]
+  fn test_provoke_forces_first_skill_on_source() {
+    let mut s1 = Skill::new("S1");
+    s1.new_component(SkillAction::damage());
+    let mut s3 = Skill::new("S3");
+    s3.new_component(SkillAction::damage().atk_rate(3.0));
+
+    let picker = AISkillPicker::new(vec![vec![s1.clone()], vec![s1.clone()], vec![s1, s3]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut provoked = hero(2);
+    provoked.apply_effect(Effect::Provoke, 1, Some(1));
+    let ss = BattleSnapshot::new(vec![hero(1), hero(1), provoked]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 2).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let ss = &leaves[0].0;
+    assert!(ss.log.events().contains(&BattleEvent::SkillUsed { hero: 2, skill: "S1".to_string() }));
+    assert_eq!(ss.heroes[0].stats.hp, 100000.0);
+    assert_eq!(ss.heroes[1].stats.hp, 100000.0 - 1000.0);
+    assert!(!ss.heroes[2].effects.has_effect(Effect::Provoke));
+  }
+
+  #[test]
+  fn test_dispel_newest_of_polarity() {
+    let mut target = hero(2);
+    target.stats.def = 1000.0;
+    target.apply_effect(Effect::AtkBuff, 2, None);
+    target.apply_effect(Effect::DefBreak, 2, Some(0));
+    target.apply_effect(Effect::Rage, 2, Some(0));
+    target.apply_effect(Effect::SpdBuff, 2, None);
+    target.apply_effect(Effect::AtkDown, 2, Some(0));
+    assert_eq!(target.stats.atk, 1000.0);
+    assert_eq!(target.stats.def, 300.0);
+
+    assert_eq!(target.dispel(Polarity::Buff, 1), vec![Effect::SpdBuff]);
+    assert_eq!(target.stats.spd, 100.0);
+
+    // Rage can't be cleansed and is skipped over.
+    assert_eq!(target.dispel(Polarity::Debuff, 5), vec![Effect::AtkDown, Effect::DefBreak]);
+    assert_eq!(target.stats.atk, 1500.0);
+    assert_eq!(target.stats.def, 1000.0);
+    assert!(target.effects.has_effect(Effect::Rage));
+    assert!(target.effects.has_effect(Effect::AtkBuff));
+  }
+
+  #[test]
+  fn test_stat_modifiers_add_up() {
+    let mut target = hero(2);
+    target.stats.def = 1000.0;
+    target.apply_effect(Effect::DefBuff, 2, None);
+    target.apply_effect(Effect::DefBreak, 2, Some(0));
+    assert!((target.stats.def - 900.0).abs() < 0.01);
+
+    // Whichever leaves first, the other is left on the base stat.
+    target.consume_effects(|x| if x.effect == Effect::DefBuff { x.duration } else { 0 });
+    assert!((target.stats.def - 300.0).abs() < 0.01);
+    target.dispel(Polarity::Debuff, 1);
+    assert_eq!(target.stats.def, 1000.0);
+
+    target.apply_effect(Effect::SpdDown, 2, Some(0));
+    target.apply_effect(Effect::SpdBuff, 2, None);
+    assert_eq!(target.stats.spd, 100.0);
+    target.apply_effect(Effect::CritResist, 2, None);
+    assert_eq!(target.stats.crit_resist, 50.0);
+  }
+
+  #[test]
+  fn test_dispel_after_reapply() {
+    let mut target = hero(2);
+    target.apply_effect(Effect::AtkBuff, 2, None);
+    target.apply_effect(Effect::SpdBuff, 2, None);
+    target.apply_effect(Effect::DefBuff, 2, None);
+
+    // Refreshed and upgraded buffs are the newest again.
+    target.apply_effect(Effect::SpdBuff, 3, None);
+    assert_eq!(target.dispel(Polarity::Buff, 1), vec![Effect::SpdBuff]);
+    target.apply_effect(Effect::GreaterAtk, 2, None);
+    assert_eq!(target.dispel(Polarity::Buff, 1), vec![Effect::GreaterAtk]);
+    assert_eq!(target.effects.entries().collect::<Vec<_>>(), vec![(Effect::DefBuff, 2)]);
+  }
+
+  #[test]
+  fn test_buff_and_debuff_caps() {
+    let mut target = hero(2);
+    for source in 0..10 {
+      assert!(target.apply_effect(Effect::Bleed(DotAmount(100.0)), 2, Some(source)));
+    }
+    assert!(!target.apply_effect(Effect::Bleed(DotAmount(100.0)), 2, Some(10)));
+    assert!(target.apply_effect(Effect::AtkBuff, 2, None));
+  }
+
+  #[test]
+  fn test_stronger_version_replaces_weaker() {
+    let mut target = hero(2);
+    assert!(target.apply_effect(Effect::AtkBuff, 2, None));
+    assert!(target.apply_effect(Effect::GreaterAtk, 1, None));
+    assert_eq!(target.stats.atk, 1750.0);
+    assert!(!target.apply_effect(Effect::AtkBuff, 3, None));
+    assert_eq!(target.effects.entries().collect::<Vec<_>>(), vec![(Effect::GreaterAtk, 1)]);
+  }
+
+  #[test]
+  fn test_reapply_refreshes_duration() {
+    let mut target = hero(2);
+    target.apply_effect(Effect::DefBreak, 1, Some(0));
+    target.apply_effect(Effect::DefBreak, 3, Some(0));
+    target.apply_effect(Effect::DefBreak, 2, Some(0));
+    assert_eq!(target.effects.entries().collect::<Vec<_>>(), vec![(Effect::DefBreak, 3)]);
+  }
+
+  #[test]
+  fn test_dot_stacks_per_source() {
+    let mut target = hero(2);
+    for turns in 1..=6 {
+      target.apply_effect(Effect::Burn(DotAmount(100.0)), turns, Some(0));
+    }
+    target.apply_effect(Effect::Burn(DotAmount(100.0)), 1, Some(1));
+
+    // The oldest stack from hero 0 was pushed out by the sixth.
+    let durations: Vec<u32> = target.effects.entries().map(|(_, turns)| turns).collect();
+    assert_eq!(durations, vec![2, 3, 4, 5, 6, 1]);
+  }
+
+  #[test]
+  fn test_dot_snapshots_applier_atk() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::effect(SkillEffect::Bleed(DotRate(0.3)), 1.0, 2));
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut target = hero(2);
+    target.stats.def = 300.0;
+    let ss = BattleSnapshot::new(vec![hero(1), target]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let mut ss = leaves.swap_remove(0).0;
+    assert_eq!(ss.heroes[1].effects.entries().next(), Some((Effect::Bleed(DotAmount(300.0)), 2)));
+
+    // Later ATK changes don't matter. 300 raw against 90 effective DEF.
+    ss.heroes[0].stats.atk = 5000.0;
+    hero_on_turn_start(&mut ss, 1);
+    let tick = 300.0 / 1.3;
+    assert!(ss.log.events().contains(&BattleEvent::DotDamage {
+      src: Some(0), target: 1, effect: Effect::Bleed(DotAmount(300.0)), amount: tick
+    }));
+    assert_eq!(ss.heroes[1].stats.hp, 100000.0 - tick);
+  }
+
+  #[test]
+  fn test_healing_over_time_not_netted_against_dots() {
+    let mut ss = BattleSnapshot::new(vec![hero(1), hero(2)]);
+    ss.heroes[1].stats.hp = 99000.0;
+    ss.heroes[1].apply_effect(Effect::ContinuousHealing, 2, Some(1));
+    ss.heroes[1].apply_effect(Effect::Bleed(DotAmount(300.0)), 2, Some(0));
+
+    // The heal lands in full, capped at max HP, before the bleed ticks on its own.
+    hero_on_turn_start(&mut ss, 1);
+    let tick = 300.0;
+    assert!(ss.log.events().iter().any(|x| matches!(*x, BattleEvent::Heal { target: 1, amount, .. } if amount == 1000.0)));
+    assert!(ss.log.events().contains(&BattleEvent::DotDamage {
+      src: Some(0), target: 1, effect: Effect::Bleed(DotAmount(300.0)), amount: tick
+    }));
+    assert_eq!(ss.heroes[1].stats.hp, 100000.0 - tick);
+  }
+
+  #[test]
+  fn test_detonate_remaining_turns() {
+    let mut skill = Skill::new("S3");
+    skill.new_component(SkillAction::Detonate { dot: DotKind::Bleed });
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut target = hero(2);
+    target.apply_effect(Effect::Bleed(DotAmount(100.0)), 2, Some(0));
+    target.apply_effect(Effect::Bleed(DotAmount(200.0)), 3, Some(0));
+    target.apply_effect(Effect::Burn(DotAmount(500.0)), 2, Some(0));
+    let ss = BattleSnapshot::new(vec![hero(1), target]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let ss = &leaves[0].0;
+
+    assert!(ss.log.events().contains(&BattleEvent::Detonated { src: 0, target: 1, stacks: 2 }));
+    assert!(ss.log.events().contains(&BattleEvent::Damage { src: Some(0), target: 1, amount: 800.0 }));
+    assert_eq!(ss.heroes[1].effects.entries().map(|(x, _)| x).collect::<Vec<_>>(), vec![Effect::Burn(DotAmount(500.0))]);
+  }
+
+  #[test]
+  fn test_shield_absorbs_before_hp() {
+    let mut skill = Skill::new("S2");
+    skill.new_component(SkillAction::Shield { rate: 0.5, scaling: Scaling::CasterAtk, duration: 2 });
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut leaves = vec![];
+    let ss = BattleSnapshot::new(vec![hero(1), hero(2)]);
+    take_turn(picker, 0).then(move |_| take_turn(picker, 0)).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let mut target = leaves.swap_remove(0).0.heroes.swap_remove(0);
+
+    // Two casts stack into one pool that outlasts the first turn end.
+    assert_eq!(target.effects.entries().collect::<Vec<_>>(), vec![(Effect::Shield(1000.0), 2)]);
+
+    let hit = DamageInstance { raw_dmg: 600.0, def_pen: 0.0, fixed: 0.0 };
+    assert_eq!(deal_damage(DamageSource::Hero(1), &mut target, &hit), Some((0.0, 600.0)));
+    assert_eq!(deal_damage(DamageSource::Hero(1), &mut target, &hit), Some((200.0, 400.0)));
+    assert_eq!(target.stats.hp, 100000.0 - 200.0);
+    assert!(!target.effects.has_effect_kind(Effect::Shield(0.0)));
+  }
+
+  #[test]
+  fn test_lifesteal_and_overheal() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage().lifesteal(25.0));
+    skill.new_component(SkillAction::Heal { rate: 2.0, scaling: Scaling::TargetMissingHp })
+      .targeting(Targeting::SelfSingle);
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    // Both in percent, adding up to half the damage.
+    let mut caster = hero(1);
+    caster.stats.hp = 99000.0;
+    caster.stats.lifesteal = 25.0;
+    let ss = BattleSnapshot::new(vec![caster, hero(2)]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let ss = &leaves[0].0;
+
+    let heals: Vec<&BattleEvent> = ss.log.events().iter().filter(|x| matches!(x, BattleEvent::Heal { .. })).collect();
+    assert_eq!(heals, vec![
+      &BattleEvent::Heal { src: Some(0), target: 0, amount: 500.0, overheal: 0.0 },
+      &BattleEvent::Heal { src: Some(0), target: 0, amount: 500.0, overheal: 500.0 },
+    ]);
+    assert_eq!(ss.heroes[0].stats.hp, 100000.0);
+  }
+
+  #[test]
+  fn test_revive_passive_once_per_battle() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage());
+    let mut passive = Passive::new("Resurrect", Trigger::Death).once();
+    passive.new_component(SkillAction::Revive { rate: 0.5 }).targeting(Targeting::SelfSingle);
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]])
+      .with_passives(vec![vec![], vec![passive]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut victim = hero(2);
+    victim.stats.hp = 500.0;
+    victim.readiness = 5000;
+    victim.apply_effect(Effect::AtkBuff, 2, None);
+    let ss = BattleSnapshot::new(vec![hero(1), victim]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let mut ss = leaves.remove(0).0;
+    let victim = &ss.heroes[1];
+    assert!(victim.alive && !ss.is_over());
+    assert_eq!(victim.stats.hp, 50000.0);
+    assert_eq!(victim.stats.atk, 1000.0);
+    assert_eq!(victim.readiness, 0);
+    assert!(ss.log.events().contains(&BattleEvent::Revived { src: 1, target: 1, hp: 50000.0 }));
+
+    ss.heroes[1].stats.hp = 500.0;
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    assert!(!leaves[0].0.heroes[1].alive);
+    assert!(leaves[0].0.is_over());
+  }
+
+  #[test]
+  fn test_immortal_and_revive_dead_ally() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage());
+    skill.new_component(SkillAction::Revive { rate: 0.25 });
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()]; 3]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut fallen = hero(1);
+    fallen.alive = false;
+    fallen.stats.hp = 0.0;
+    let mut target = hero(2);
+    target.stats.hp = 500.0;
+    target.apply_effect(Effect::Immortal, 2, None);
+    let ss = BattleSnapshot::new(vec![hero(1), fallen, target]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    let ss = &leaves[0].0;
+
+    assert!(ss.heroes[2].alive);
+    assert_eq!(ss.heroes[2].stats.hp, 1.0);
+    assert!(ss.log.events().contains(&BattleEvent::Damage { src: Some(0), target: 2, amount: 499.0 }));
+    assert!(ss.heroes[1].alive);
+    assert_eq!(ss.heroes[1].stats.hp, 25000.0);
+    assert_eq!(Targeting::DeadAllies.get_target(ss, 0), Vec::<HeroID>::new());
+  }
+
+  #[test]
+  fn test_damage_scaling_terms() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage()
+      .atk_rate(0.0)
+      .scaling(Scaling::CasterMaxHp, 0.01)
+      .scaling(Scaling::TargetHp, 0.01)
+      .per_debuff(0.5)
+      .fixed(100.0));
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut target = hero(2);
+    target.stats.hp = 50000.0;
+    target.stats.def = 300.0;
+    target.apply_effect(Effect::AtkDown, 2, None);
+    target.apply_effect(Effect::SpdDown, 2, None);
+    let ss = BattleSnapshot::new(vec![hero(1), target]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+
+    // (1000 + 500) * (1 + 2 * 0.5), halved by DEF, plus 100 fixed.
+    assert_eq!(leaves[0].0.heroes[1].stats.hp, 50000.0 - 1600.0);
+  }
+
+  #[test]
+  fn test_splash_never_misses_or_crits() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::Splash { atk_rate: 0.5, def_pen: 0.0 }).targeting(Targeting::EnemyAOE);
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]]);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut attacker = hero(1);
+    attacker.stats.cc = 50.0;
+    attacker.stats.hit_chance = 50.0;
+    let ss = BattleSnapshot::new(vec![attacker, hero(2), hero(2)]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss, p)));
+    assert_eq!(leaves.len(), 1);
+    assert_eq!(leaves[0].0.heroes[1].stats.hp, 100000.0 - 500.0);
+    assert_eq!(leaves[0].0.heroes[2].stats.hp, 100000.0 - 500.0);
+  }
+
+  #[test]
+  fn test_attack_proc_decreases_cr() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage());
+    let mut proc = Passive::new("Rod", Trigger::Attack).chance(0.25);
+    proc.new_component(SkillAction::ChangeCr { amount: -0.25 }).targeting(Targeting::Trigger);
+    let rod = Artifact { name: "Rod".to_string(), atk: 250.0, hp: 0.0, procs: vec![proc] };
+
+    let picker = AISkillPicker::new(vec![vec![skill.clone()], vec![skill]])
+      .with_artifact(0, &rod);
+    let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+    let mut target = hero(2);
+    target.readiness = 5000;
+    let ss = BattleSnapshot::new(vec![hero(1), target]);
+
+    let mut leaves = vec![];
+    take_turn(picker, 0).expand(ss, &mut |ss, p| leaves.push((ss.heroes[1].readiness, p)));
+    assert_eq!(leaves, vec![(2500, 0.25), (5000, 0.75)]);
+  }
+
+  #[test]
+  fn test_artifact_procs_kept_whatever_the_order() {
+    let own = Passive::new("Own", Trigger::Attacked);
+    let proc = Passive::new("Rod", Trigger::Attack);
+    let rod = Artifact { name: "Rod".to_string(), atk: 0.0, hp: 0.0, procs: vec![proc] };
+    let names = |picker: &AISkillPicker| picker.passives(0).iter().map(|x| x.name.clone()).collect::<Vec<_>>();
+
+    let before = AISkillPicker::new(vec![]).with_artifact(0, &rod).with_passives(vec![vec![own.clone()]]);
+    let after = AISkillPicker::new(vec![]).with_passives(vec![vec![own]]).with_artifact(0, &rod);
+    assert_eq!(names(&before), vec!["Own", "Rod"]);
+    assert_eq!(names(&after), vec!["Own", "Rod"]);
+    assert!(before.passives(1).is_empty());
+  }
+}
@@ -0,0 +1,552 @@
+use crate::{
+  battle::*,
+  damage::damage_distribution,
+  data::{ DataError, Roster },
+  optimizer::{ Metric, Optimizer, Stat },
+  speed::{ self, StartCr },
+  scheduler,
+  stats::OutcomeStats
+};
+use serde::Serialize;
+use std::fmt;
+use std::sync::{ Arc, Mutex };
+
+const USAGE: &str = "\
+usage:
+  EALD simulate <team-a> <team-b> [options]
+  EALD replay <team-a> <team-b> [--top <n>] [options]
+  EALD skill-dmg <attacker> <defender> <skill> [options]
+  EALD optimize <hero> <team-a> <team-b> [--metric <metric>] [--min-spd <n>]
+                [--per-slot <n>] [--candidates <n>] [options]
+  EALD turn-order <team-a> <team-b> <turns> [--start-cr <pct>] [options]
+  EALD outspeed <hero> <other> <team-a> <team-b> [--start-cr <pct>] [options]
+  EALD export <team-a> <team-b> <file>
+
+Teams and heroes are looked up by name in the roster file. `optimize` picks
+gear for <hero> from the roster's gear pool and always samples. `export`
+writes the two teams and the gear pool to <file>, .toml or .json.
+
+options:
+  --data <file>        roster file, .toml or .json (default: roster.toml)
+  --mode <mode>        exhaustive or monte-carlo (default: exhaustive)
+  --samples <n>        paths to sample in monte-carlo mode (default: 10000)
+  --seed <n>           seed for monte-carlo mode (default: 0)
+  --depth <n>          branch depth limit in exhaustive mode (default: 12)
+  --turns <n>          turn limit of a battle (default: 30)
+  --threads <n>        worker threads (default: 8)
+  --top <n>            number of replays or builds to print (default: 3)
+  --metric <metric>    win-rate, damage or survival (default: win-rate)
+  --min-spd <n>        SPD every build must reach
+  --per-slot <n>       gear pieces of each set kept per slot (default: 3)
+  --candidates <n>     builds simulated, best gear score first (default: 20)
+  --start-cr <pct>     heroes start up to this much CR above their own (default: 0)
+  --allow-partial      report exhaustive results even if under 95% of battles
+                       finished within --depth
+  --json               print JSON instead of text";
+
+#[derive(Debug)]
+pub enum CliError {
+  Usage(String),
+  Data(DataError),
+  Json(serde_json::Error),
+}
+
+impl fmt::Display for CliError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CliError::Usage(e) => write!(f, "{}\n\n{}", e, USAGE),
+      CliError::Data(e) => write!(f, "{}", e),
+      CliError::Json(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl From<DataError> for CliError {
+  fn from(e: DataError) -> Self {
+    CliError::Data(e)
+  }
+}
+
+impl From<serde_json::Error> for CliError {
+  fn from(e: serde_json::Error) -> Self {
+    CliError::Json(e)
+  }
+}
+
+fn usage(message: impl Into<String>) -> CliError {
+  CliError::Usage(message.into())
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Mode {
+  Exhaustive,
+  MonteCarlo,
+}
+
+struct Options {
+  data: String,
+  mode: Mode,
+  samples: u32,
+  seed: u64,
+  depth: i32,
+  turns: u32,
+  threads: usize,
+  top: usize,
+  metric: Metric,
+  min_spd: Option<f32>,
+  per_slot: usize,
+  candidates: usize,
+  start_cr: f32,
+  allow_partial: bool,
+  json: bool,
+}
+
+impl Default for Options {
+  fn default() -> Self {
+    Options {
+      data: "roster.toml".to_string(),
+      mode: Mode::Exhaustive,
+      samples: 10000,
+      seed: 0,
+      depth: 12,
+      turns: 30,
+      threads: 8,
+      top: 3,
+      metric: Metric::WinRate,
+      min_spd: None,
+      per_slot: 3,
+      candidates: 20,
+      start_cr: 0.0,
+      allow_partial: false,
+      json: false,
+    }
+  }
+}
+
+// Split arguments into positionals and parsed `--options`.
+fn parse_args(args: &[String]) -> Result<(Vec<&str>, Options), CliError> {
+  fn value<T: std::str::FromStr>(name: &str, value: Option<&String>) -> Result<T, CliError> {
+    let value = value.ok_or_else(|| usage(format!("{} needs a value", name)))?;
+    value.parse().map_err(|_| usage(format!("invalid value `{}` for {}", value, name)))
+  }
+
+  let mut positional = vec![];
+  let mut opts = Options::default();
+  let mut args = args.iter();
+
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--data" => opts.data = value("--data", args.next())?,
+      "--mode" => opts.mode = match args.next().map(String::as_str) {
+        Some("exhaustive") => Mode::Exhaustive,
+        Some("monte-carlo") => Mode::MonteCarlo,
+        _ => return Err(usage("--mode must be exhaustive or monte-carlo")),
+      },
+      "--samples" => opts.samples = value("--samples", args.next())?,
+      "--seed" => opts.seed = value("--seed", args.next())?,
+      "--depth" => opts.depth = value("--depth", args.next())?,
+      "--turns" => opts.turns = value("--turns", args.next())?,
+      "--threads" => opts.threads = value("--threads", args.next())?,
+      "--top" => opts.top = value("--top", args.next())?,
+      "--metric" => opts.metric = match args.next().map(String::as_str) {
+        Some("win-rate") => Metric::WinRate,
+        Some("damage") => Metric::DamageDealt,
+        Some("survival") => Metric::Survival,
+        _ => return Err(usage("--metric must be win-rate, damage or survival")),
+      },
+      "--min-spd" => opts.min_spd = Some(value("--min-spd", args.next())?),
+      "--per-slot" => opts.per_slot = value("--per-slot", args.next())?,
+      "--candidates" => opts.candidates = value("--candidates", args.next())?,
+      "--start-cr" => opts.start_cr = value("--start-cr", args.next())?,
+      "--allow-partial" => opts.allow_partial = true,
+      "--json" => opts.json = true,
+      x if x.starts_with("--") => return Err(usage(format!("unknown option {}", x))),
+      x => positional.push(x),
+    }
+  }
+  if opts.samples == 0 || opts.threads == 0 || opts.per_slot == 0 || opts.candidates == 0 {
+    return Err(usage("--samples, --threads, --per-slot and --candidates must be at least 1"));
+  }
+  Ok((positional, opts))
+}
+
+// Entry point of the binary. `args` excludes the program name.
+pub fn run(args: &[String]) -> Result<(), CliError> {
+  let (positional, opts) = parse_args(args)?;
+
+  match positional.as_slice() {
+    ["simulate", a, b] => simulate(a, b, &opts, false),
+    ["replay", a, b] => simulate(a, b, &opts, true),
+    ["skill-dmg", attacker, defender, skill] => skill_dmg(attacker, defender, skill, &opts),
+    ["optimize", hero, a, b] => optimize(hero, a, b, &opts),
+    ["turn-order", a, b, turns] => {
+      let turns = turns.parse().map_err(|_| usage(format!("invalid number of turns `{}`", turns)))?;
+      turn_order(a, b, turns, &opts)
+    }
+    ["outspeed", hero, other, a, b] => outspeed(hero, other, a, b, &opts),
+    ["export", a, b, file] => Ok(matchup(&Roster::load(&opts.data)?, a, b)?.save(file)?),
+    [] => Err(usage("missing command")),
+    [cmd, ..] => Err(usage(format!("bad arguments for `{}`", cmd))),
+  }
+}
+
+fn matchup(roster: &Roster, a: &str, b: &str) -> Result<Roster, CliError> {
+  let team = |name: &str| {
+    roster.team(name).cloned().ok_or_else(|| usage(format!("no team named `{}`", name)))
+  };
+  Ok(Roster { teams: vec![team(a)?, team(b)?], gear: roster.gear.clone() })
+}
+
+fn simulate(a: &str, b: &str, opts: &Options, replay: bool) -> Result<(), CliError> {
+  let roster = matchup(&Roster::load(&opts.data)?, a, b)?;
+  let stats = run_battles(&roster, opts, if replay { opts.top } else { 0 })?;
+  if replay && stats.replays().is_empty() {
+    return Err(usage(format!(
+      "no battle finished within --depth {} ({:.2}% cut at the depth limit)",
+      opts.depth, stats.truncated() * 100.0
+    )));
+  }
+  if !replay {
+    check_coverage(&stats, opts)?;
+  }
+
+  match (replay, opts.json) {
+    (false, false) => print!("{}", stats),
+    (false, true) => println!("{}", serde_json::to_string_pretty(&stats.summary())?),
+    (true, false) => {
+      let names = stats.names();
+      for (probability, log) in stats.replays() {
+        println!("Outcome [{:.2}%]\n{}", probability * 100.0, log.replay(&names));
+      }
+    }
+    (true, true) => {
+      #[derive(Serialize)]
+      struct Replay<'a> {
+        probability: f32,
+        events: &'a crate::combat_log::CombatLog,
+      }
+      let replays: Vec<_> = stats.replays().iter()
+        .map(|(probability, events)| Replay { probability: *probability, events })
+        .collect();
+      println!("{}", serde_json::to_string_pretty(&replays)?);
+    }
+  }
+  Ok(())
+}
+
+// Share of the battle tree an exhaustive run must finish for its rates to be
+// trusted. The rest is cut at the depth limit and left out of every figure.
+const MIN_COVERAGE: f32 = 0.95;
+
+fn check_coverage(stats: &OutcomeStats, opts: &Options) -> Result<(), CliError> {
+  if opts.mode == Mode::Exhaustive && !opts.allow_partial && stats.coverage() < MIN_COVERAGE {
+    return Err(usage(format!(
+      "only {:.2}% of battles finished within --depth {}; raise it, use --mode monte-carlo or pass --allow-partial",
+      stats.coverage() * 100.0, opts.depth
+    )));
+  }
+  Ok(())
+}
+
+// Outcomes of every battle between the teams of `roster`, seen from the
+// first team.
+fn run_battles(roster: &Roster, opts: &Options, replays: usize) -> Result<OutcomeStats, CliError> {
+  let (ss, skills) = roster.build();
+  if let Some(i) = skills.iter().position(|x| x.is_empty()) {
+    return Err(usage(format!("{} has no skills", ss.heroes[i].name)));
+  }
+
+  // The battle tree borrows skills from the picker for as long as it runs.
+  let picker = roster.picker();
+  let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+  let observer = Arc::new(Mutex::new(OutcomeStats::new(1).with_replays(replays)));
+
+  scheduler::set_num_threads(opts.threads);
+  let turns = opts.turns;
+  match opts.mode {
+    Mode::Exhaustive => {
+      scheduler::set_max_depth(opts.depth);
+      scheduler::rng_node_run(battle(picker, turns), ss, Arc::clone(&observer), 0);
+    }
+    Mode::MonteCarlo => {
+      scheduler::rng_node_sample(move || battle(picker, turns), ss, Arc::clone(&observer), opts.samples, opts.seed);
+    }
+  }
+  scheduler::rng_node_join();
+
+  let mut stats = observer.lock().unwrap();
+  Ok(std::mem::replace(&mut *stats, OutcomeStats::new(1)))
+}
+
+fn skill_dmg(attacker: &str, defender: &str, skill: &str, opts: &Options) -> Result<(), CliError> {
+  let roster = Roster::load(&opts.data)?;
+  let find = |name: &str| {
+    roster.teams.iter()
+      .flat_map(|x| &x.heroes)
+      .find(|x| x.name == name)
+      .ok_or_else(|| usage(format!("no hero named `{}`", name)))
+  };
+  let (attacker, defender) = (find(attacker)?, find(defender)?);
+  let skill_def = attacker.skills.iter()
+    .find(|x| x.name == skill)
+    .ok_or_else(|| usage(format!("{} has no skill `{}`", attacker.name, skill)))?;
+
+  let dist = damage_distribution(&attacker.to_hero(1), &defender.to_hero(2), skill_def);
+
+  if opts.json {
+    #[derive(Serialize)]
+    struct Report<'a> {
+      kill_chance: f32,
+      expected_damage: f32,
+      outcomes: &'a [crate::damage::DamageOutcome],
+    }
+    let report = Report { kill_chance: dist.kill_chance(), expected_damage: dist.expected_damage(), outcomes: &dist.outcomes };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+  } else {
+    // Each outcome with its chance, then the chance of at least that much.
+    println!("{} {} -> {}", attacker.name, skill, defender.name);
+    for outcome in &dist.outcomes {
+      println!(
+        "{:>8.0} dmg  {:>6.2}%  {:>6.2}% at least  {:?}{}{}",
+        outcome.damage,
+        outcome.probability * 100.0,
+        dist.chance_at_least(outcome.damage) * 100.0,
+        outcome.hits,
+        if outcome.procs.is_empty() { String::new() } else { format!(" + {:?}", outcome.procs) },
+        if outcome.kill { "  KILL" } else { "" }
+      );
+    }
+    println!("expected {:.0} dmg, kill chance {:.2}%", dist.expected_damage(), dist.kill_chance() * 100.0);
+  }
+  Ok(())
+}
+
+fn optimize(hero: &str, a: &str, b: &str, opts: &Options) -> Result<(), CliError> {
+  let roster = matchup(&Roster::load(&opts.data)?, a, b)?;
+  let id = roster.teams.iter()
+    .flat_map(|x| &x.heroes)
+    .position(|x| x.name == hero)
+    .ok_or_else(|| usage(format!("no hero named `{}` in {} or {}", hero, a, b)))?;
+  if let Some(def) = roster.teams.iter().flat_map(|x| &x.heroes).find(|x| x.skills.is_empty()) {
+    return Err(usage(format!("{} has no skills", def.name)));
+  }
+
+  let picker = roster.picker();
+  let picker: &'static AISkillPicker = Box::leak(Box::new(picker));
+
+  scheduler::set_num_threads(opts.threads);
+  let mut optimizer = Optimizer::new(opts.metric)
+    .per_slot(opts.per_slot)
+    .candidates(opts.candidates)
+    .samples(opts.samples, opts.seed)
+    .max_turns(opts.turns);
+  if let Some(spd) = opts.min_spd {
+    optimizer = optimizer.require(Stat::Spd, spd);
+  }
+  let builds = optimizer.optimize(&roster, picker, id, &roster.gear).map_err(|e| usage(e.to_string()))?;
+
+  if opts.json {
+    #[derive(Serialize)]
+    struct Report<'a> {
+      score: f32,
+      spd: f32,
+      gear: &'a [crate::gear::Gear],
+    }
+    let report: Vec<_> = builds.iter()
+      .take(opts.top)
+      .map(|x| Report { score: x.score, spd: x.stats.spd, gear: &x.gear })
+      .collect();
+    println!("{}", serde_json::to_string_pretty(&report)?);
+  } else if builds.is_empty() {
+    println!("no build meets the requirements");
+  } else {
+    for build in builds.iter().take(opts.top) {
+      let stats = &build.stats;
+      println!(
+        "{:.4}: hp {:.0} atk {:.0} def {:.0} spd {:.0} cc {:.0} cdmg {:.0} eff {:.0} effres {:.0}",
+        build.score, stats.max_hp, stats.atk, stats.def, stats.spd, stats.cc, stats.cdmg, stats.eff, stats.effres
+      );
+      for piece in &build.gear {
+        println!("  {:?} {:?}: {:?} {}", piece.slot, piece.set, piece.main.stat, piece.main.value);
+      }
+    }
+  }
+  Ok(())
+}
+
+// Values each random starting CR is split into.
+const START_CR_STEPS: u32 = 5;
+
+fn start_crs(ss: &BattleSnapshot, opts: &Options) -> Vec<StartCr> {
+  let spread = (opts.start_cr * CR_FULL as f32 / 100.0) as u32;
+  ss.heroes.iter().map(|x| StartCr::range(x.readiness, x.readiness + spread)).collect()
+}
+
+fn turn_order(a: &str, b: &str, turns: usize, opts: &Options) -> Result<(), CliError> {
+  let (ss, _) = matchup(&Roster::load(&opts.data)?, a, b)?.build();
+  let order = speed::turn_order(&ss, &start_crs(&ss, opts), turns, START_CR_STEPS)
+    .ok_or_else(|| usage("every hero needs a starting CR"))?;
+  let names = |order: &[HeroID]| order.iter().map(|&i| ss.heroes[i].name.as_str()).collect::<Vec<_>>();
+
+  if opts.json {
+    #[derive(Serialize)]
+    struct Order<'a> {
+      probability: f32,
+      heroes: Vec<&'a str>,
+    }
+    let report: Vec<_> = order.orders.iter()
+      .take(opts.top)
+      .map(|(x, p)| Order { probability: *p, heroes: names(x) })
+      .collect();
+    println!("{}", serde_json::to_string_pretty(&report)?);
+  } else {
+    for (x, p) in order.orders.iter().take(opts.top) {
+      println!("{:>6.2}%  {}", p * 100.0, names(x).join(", "));
+    }
+    for (i, hero) in ss.heroes.iter().enumerate() {
+      println!("{} takes the first turn {:.2}%", hero.name, order.chance_at(0, i) * 100.0);
+    }
+  }
+  Ok(())
+}
+
+fn outspeed(hero: &str, other: &str, a: &str, b: &str, opts: &Options) -> Result<(), CliError> {
+  let (ss, _) = matchup(&Roster::load(&opts.data)?, a, b)?.build();
+  let find = |name: &str| {
+    ss.heroes.iter().position(|x| x.name == name).ok_or_else(|| usage(format!("no hero named `{}` in {} or {}", name, a, b)))
+  };
+  let (id, other_id) = (find(hero)?, find(other)?);
+  if id == other_id {
+    return Err(usage(format!("`{}` can't outspeed themselves", hero)));
+  }
+
+  let starts = start_crs(&ss, opts);
+  match speed::min_spd_to_outspeed(&ss, &starts, id, other_id) {
+    Some(spd) => println!("{} needs {:.0} SPD (has {:.0}) to always act before {}", hero, spd, ss.heroes[id].stats.spd, other),
+    None => println!("{} can't always act before {}", hero, other),
+  }
+  // Every hero gets a turn in the first round unless someone laps them.
+  let order = speed::turn_order(&ss, &starts, ss.heroes.len(), START_CR_STEPS)
+    .ok_or_else(|| usage("every hero needs a starting CR"))?;
+  println!("At {:.0} SPD, {} acts before {} {:.2}%", ss.heroes[id].stats.spd, hero, other, order.chance_before(id, other_id) * 100.0);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MATCHUP: &str = r#"
+    [[teams]]
+    name = "Ally"
+
+    [[teams.heroes]]
+    name = "Striker"
+    element = "fire"
+    stats = { max_hp = 3000, atk = 1000, def = 0, spd = 120, cc = 30 }
+    skills = [{ name = "S1", components = [{ action = { type = "damage" } }] }]
+
+    [[teams]]
+    name = "Enemy"
+
+    [[teams.heroes]]
+    name = "Dummy"
+    element = "ice"
+    stats = { max_hp = 3000, atk = 1000, def = 0, spd = 100, cc = 30 }
+    skills = [{ name = "S1", components = [{ action = { type = "damage" } }] }]
+  "#;
+
+  fn args(line: &str) -> Vec<String> {
+    line.split_whitespace().map(String::from).collect()
+  }
+
+  #[test]
+  fn test_parse_args() {
+    let line = args("simulate A B --mode monte-carlo --seed 7 --depth 4 --threads 2 --json");
+    let (positional, opts) = parse_args(&line).unwrap();
+    assert_eq!(positional, vec!["simulate", "A", "B"]);
+    assert_eq!(opts.mode, Mode::MonteCarlo);
+    assert_eq!((opts.seed, opts.depth, opts.threads), (7, 4, 2));
+    assert!(opts.json);
+
+    let (_, opts) = parse_args(&args("optimize S A B --per-slot 2 --candidates 5")).unwrap();
+    assert_eq!((opts.per_slot, opts.candidates), (2, 5));
+
+    let error = |line: &str| match parse_args(&args(line)) {
+      Err(CliError::Usage(e)) => e,
+      _ => panic!("expected a usage error for `{}`", line),
+    };
+    assert_eq!(error("simulate A B --fast"), "unknown option --fast");
+    assert_eq!(error("simulate A B --seed x"), "invalid value `x` for --seed");
+    assert_eq!(error("simulate A B --depth"), "--depth needs a value");
+    assert_eq!(error("simulate A B --mode quick"), "--mode must be exhaustive or monte-carlo");
+    assert_eq!(error("simulate A B --threads 0"), "--samples, --threads, --per-slot and --candidates must be at least 1");
+  }
+
+  #[test]
+  fn test_run_errors() {
+    let path = std::env::temp_dir().join(format!("eald-cli-{}.toml", std::process::id()));
+    std::fs::write(&path, MATCHUP).unwrap();
+    let data = path.to_str().unwrap();
+
+    let error = |line: String| match run(&args(&line)) {
+      Err(CliError::Usage(e)) => e,
+      Err(e) => panic!("expected a usage error for `{}`, got {}", line, e),
+      Ok(()) => panic!("expected a usage error for `{}`", line),
+    };
+    assert_eq!(error(String::new()), "missing command");
+    assert_eq!(error("simulate Ally".to_string()), "bad arguments for `simulate`");
+    assert_eq!(error(format!("turn-order Ally Enemy soon --data {}", data)), "invalid number of turns `soon`");
+    assert_eq!(error(format!("simulate Ally Nobody --data {}", data)), "no team named `Nobody`");
+    assert_eq!(error(format!("skill-dmg Striker Dummy S2 --data {}", data)), "Striker has no skill `S2`");
+    assert_eq!(error(format!("outspeed Striker Striker Ally Enemy --data {}", data)), "`Striker` can't outspeed themselves");
+    assert!(matches!(run(&args("simulate Ally Enemy --data missing.toml")), Err(CliError::Data(_))));
+
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_partial_coverage() {
+    use crate::scheduler::RngObserver;
+
+    let mut stats = OutcomeStats::new(1);
+    stats.rng_did_truncate(BattleSnapshot::new(vec![]), 12, 1.0);
+    let (_, opts) = parse_args(&args("simulate A B")).unwrap();
+    assert!(matches!(check_coverage(&stats, &opts), Err(CliError::Usage(_))));
+    let (_, opts) = parse_args(&args("simulate A B --allow-partial")).unwrap();
+    assert!(check_coverage(&stats, &opts).is_ok());
+    let (_, opts) = parse_args(&args("simulate A B --mode monte-carlo")).unwrap();
+    assert!(check_coverage(&stats, &opts).is_ok());
+  }
+
+  #[test]
+  fn test_export_matchup() {
+    let dir = std::env::temp_dir();
+    let data = dir.join(format!("eald-export-{}.toml", std::process::id()));
+    let out = dir.join(format!("eald-export-{}.json", std::process::id()));
+    std::fs::write(&data, MATCHUP).unwrap();
+
+    run(&args(&format!("export Enemy Ally {} --data {}", out.display(), data.display()))).unwrap();
+    let exported = Roster::load(&out).unwrap();
+    let names: Vec<&str> = exported.teams.iter().map(|x| x.name.as_str()).collect();
+    assert_eq!(names, vec!["Enemy", "Ally"]);
+    assert_eq!(exported.teams[1].heroes[0].stats.cc, 30.0);
+
+    std::fs::remove_file(&data).unwrap();
+    std::fs::remove_file(&out).unwrap();
+  }
+
+  #[test]
+  fn test_seeded_monte_carlo_is_deterministic() {
+    let roster = Roster::parse(MATCHUP, crate::data::Format::Toml).unwrap();
+    let sample = |seed: &str| {
+      let (_, opts) = parse_args(&args(&format!("--mode monte-carlo --samples 200 --threads 4 --seed {}", seed))).unwrap();
+      let stats = run_battles(&roster, &opts, 0).unwrap();
+      assert_eq!(stats.leaves(), 200);
+      serde_json::to_string(&stats.summary()).unwrap()
+    };
+
+    assert_eq!(sample("3"), sample("3"));
+    assert_ne!(sample("3"), sample("4"));
+  }
+}
@@ -0,0 +1,169 @@
+use crate::battle::{ Effect, HeroID };
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HitKind {
+  Normal,
+  Crit,
+  Miss,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BattleEvent {
+  TurnStart { turn: u32, hero: HeroID },
+  SkillUsed { hero: HeroID, skill: String },
+  DualAttack { hero: HeroID, with: HeroID },
+  PassiveTriggered { hero: HeroID, passive: String },
+  Counter { hero: HeroID, target: HeroID },
+  ExtraTurn { hero: HeroID },
+  EffectApplied { target: HeroID, effect: Effect, duration: u32, chance: f32 },
+  // The skill's own chance failed, before any resist roll.
+  EffectMissed { target: HeroID, effect: Effect, chance: f32 },
+  EffectResisted { target: HeroID, effect: Effect, chance: f32 },
+  // Immunity, CannotBuff or no room left.
+  EffectBlocked { target: HeroID, effect: Effect },
+  TurnSkipped { hero: HeroID },
+  EffectRemoved { target: HeroID, effect: Effect },
+  Detonated { src: HeroID, target: HeroID, stacks: u32 },
+  Attack { src: HeroID, target: HeroID, hit: HitKind, chance: f32 },
+  Damage { src: Option<HeroID>, target: HeroID, amount: f32 },
+  // A tick of damage over time; `src` applied the effect.
+  DotDamage { src: Option<HeroID>, target: HeroID, effect: Effect, amount: f32 },
+  ShieldAbsorbed { target: HeroID, amount: f32 },
+  // `overheal` is the part of the heal that went past max HP.
+  Heal { src: Option<HeroID>, target: HeroID, amount: f32, overheal: f32 },
+  Death { hero: HeroID },
+  Revived { src: HeroID, target: HeroID, hp: f32 },
+  // Share of a full CR gauge gained, or lost if negative.
+  CrChanged { target: HeroID, amount: f32 },
+}
+
+// Ordered record of everything that happened along one path of the rng tree.
+// Every branch owns its own copy, so a leaf's log is exactly its history.
+#[derive(Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct CombatLog {
+  events: Vec<BattleEvent>,
+}
+
+impl CombatLog {
+  pub fn new() -> Self {
+    CombatLog { events: vec![] }
+  }
+
+  pub fn push(&mut self, event: BattleEvent) {
+    self.events.push(event);
+  }
+
+  pub fn events(&self) -> &[BattleEvent] {
+    &self.events
+  }
+
+  // `names[i]` is the name of hero `i`.
+  pub fn replay<'a>(&'a self, names: &'a [String]) -> Replay<'a> {
+    Replay { log: self, names }
+  }
+}
+
+impl BattleEvent {
+  // One line of a replay, calling hero `i` by `names[i]`.
+  fn write(&self, f: &mut fmt::Formatter, names: &[String]) -> fmt::Result {
+    use BattleEvent::*;
+
+    let n = |hero: &HeroID| &names[*hero];
+    match self {
+      TurnStart { turn, hero } => write!(f, "Turn {}: {}", turn, n(hero)),
+      SkillUsed { hero, skill } => write!(f, "{} uses {}", n(hero), skill),
+      DualAttack { hero, with } => write!(f, "{} joins {} in a dual attack", n(hero), n(with)),
+      PassiveTriggered { hero, passive } => write!(f, "{} triggers {}", n(hero), passive),
+      Counter { hero, target } => write!(f, "{} counters {}", n(hero), n(target)),
+      ExtraTurn { hero } => write!(f, "{} gets an extra turn", n(hero)),
+      EffectApplied { target, effect, duration, chance } => write!(
+        f, "{:?} ({} turns) applied to {} [{:.1}%]",
+        effect, duration, n(target), chance * 100.0
+      ),
+      EffectMissed { target, effect, chance } => write!(
+        f, "{:?} missed {} [{:.1}%]", effect, n(target), chance * 100.0
+      ),
+      EffectResisted { target, effect, chance } => write!(
+        f, "{} resisted {:?} [{:.1}%]", n(target), effect, chance * 100.0
+      ),
+      EffectBlocked { target, effect } => write!(f, "{:?} blocked on {}", effect, n(target)),
+      TurnSkipped { hero } => write!(f, "{} can't act", n(hero)),
+      EffectRemoved { target, effect } => write!(f, "{:?} removed from {}", effect, n(target)),
+      Detonated { src, target, stacks } => write!(f, "{} detonates {} stacks on {}", n(src), stacks, n(target)),
+      Attack { src, target, hit, chance } => write!(
+        f, "{} attacks {}: {:?} [{:.1}%]", n(src), n(target), hit, chance * 100.0
+      ),
+      Damage { src: Some(src), target, amount } => write!(
+        f, "{} deals {:.0} damage to {}", n(src), amount, n(target)
+      ),
+      Damage { src: None, target, amount } => write!(
+        f, "{} takes {:.0} damage from effects", n(target), amount
+      ),
+      DotDamage { src: Some(src), target, effect, amount } => write!(
+        f, "{} takes {:.0} damage from {:?} of {}", n(target), amount, effect, n(src)
+      ),
+      DotDamage { src: None, target, effect, amount } => write!(
+        f, "{} takes {:.0} damage from {:?}", n(target), amount, effect
+      ),
+      ShieldAbsorbed { target, amount } => write!(f, "{}'s shield absorbs {:.0} damage", n(target), amount),
+      Heal { src: Some(src), target, amount, .. } if src != target => write!(
+        f, "{} heals {} for {:.0}", n(src), n(target), amount
+      ),
+      Heal { target, amount, .. } => write!(f, "{} heals {:.0}", n(target), amount),
+      Death { hero } => write!(f, "{} dies", n(hero)),
+      Revived { src, target, hp } if src != target => write!(
+        f, "{} revives {} with {:.0} HP", n(src), n(target), hp
+      ),
+      Revived { target, hp, .. } => write!(f, "{} revives with {:.0} HP", n(target), hp),
+      CrChanged { target, amount } => write!(f, "{}'s CR {:+.0}%", n(target), amount * 100.0),
+    }
+  }
+}
+
+// A log ready to print, with the names of the heroes it refers to.
+pub struct Replay<'a> {
+  log: &'a CombatLog,
+  names: &'a [String],
+}
+
+// Turn-by-turn replay: turn headers flush left, everything else indented below.
+impl fmt::Display for Replay<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for event in &self.log.events {
+      if !matches!(event, BattleEvent::TurnStart { .. }) {
+        write!(f, "  ")?;
+      }
+      event.write(f, self.names)?;
+      writeln!(f)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_replay_format() {
+    let mut log = CombatLog::new();
+    log.push(BattleEvent::TurnStart { turn: 1, hero: 0 });
+    log.push(BattleEvent::SkillUsed { hero: 0, skill: "S1".to_string() });
+    log.push(BattleEvent::EffectApplied { target: 1, effect: Effect::AtkDown, duration: 2, chance: 0.25 });
+    log.push(BattleEvent::Damage { src: None, target: 1, amount: 120.0 });
+
+    let names = vec!["Ras".to_string(), "Kise".to_string()];
+    assert_eq!(
+      log.replay(&names).to_string(),
+      "Turn 1: Ras\n\
+       \x20 Ras uses S1\n\
+       \x20 AtkDown (2 turns) applied to Kise [25.0%]\n\
+       \x20 Kise takes 120 damage from effects\n"
+    );
+  }
+}
@@ -0,0 +1,497 @@
+use crate::battle::{ Effect, Hero, HeroID, Polarity, SkillUse };
+use serde::{ Serialize, Deserialize };
+use std::convert::TryFrom;
+use std::fmt;
+
+// Predicate deciding whether a skill component activates, written in data
+// files as a small expression language:
+//
+//   target.hp < 50% and not target.has(immunity)
+//   target.debuffs > 0 and caster.buffs >= 2
+//   caster.has(atk_buff) or (target.spd >= 200)
+//   target.crit or skill.kill
+//   skill.damage >= 5000 and target.damage > 0
+//
+// `hp` is a percentage of max HP, `buffs` and `debuffs` count the effects of
+// that polarity, other stats are compared as is.
+// `skill` and `target.crit`/`target.damage` read what this use of the skill
+// has done so far, see `SkillUse`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Condition {
+  Not(Box<Condition>),
+  And(Box<Condition>, Box<Condition>),
+  Or(Box<Condition>, Box<Condition>),
+  Compare { subject: Subject, stat: Stat, cmp: Cmp, value: f32 },
+  HasEffect { subject: Subject, effect: Effect },
+  Alive(Subject),
+  TargetCrit,
+  SkillCrit,
+  SkillKill,
+}
+
+// What a condition is evaluated against: one target of one skill component.
+pub struct ConditionContext<'a> {
+  pub caster: &'a Hero,
+  pub target: &'a Hero,
+  pub target_id: HeroID,
+  pub skill_use: &'a SkillUse,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Subject {
+  Caster,
+  Target,
+  Skill,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stat {
+  HpPercent,
+  Atk,
+  Def,
+  Spd,
+  Cc,
+  Eff,
+  EffRes,
+  Buffs,
+  Debuffs,
+  // Dealt by the current skill use, to the target or in total.
+  Damage,
+  Hits,
+  Crits,
+  Kills,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Cmp {
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  Eq,
+  Ne,
+}
+
+impl Condition {
+  pub fn eval(&self, ctx: &ConditionContext) -> bool {
+    use Condition::*;
+
+    // The parser only pairs `Skill` with skill stats, for which the hero
+    // is never looked at.
+    let subject = |s: &Subject| match s {
+      Subject::Caster | Subject::Skill => ctx.caster,
+      Subject::Target => ctx.target,
+    };
+    let skill = ctx.skill_use;
+
+    match self {
+      Not(c) => !c.eval(ctx),
+      And(a, b) => a.eval(ctx) && b.eval(ctx),
+      Or(a, b) => a.eval(ctx) || b.eval(ctx),
+      Compare { subject: s, stat, cmp, value } => {
+        let lhs = match (s, stat) {
+          (Subject::Target, Stat::Damage) => skill.damage_to(ctx.target_id),
+          (_, Stat::Damage) => skill.total_damage(),
+          (_, Stat::Hits) => skill.hits.len() as f32,
+          (_, Stat::Crits) => skill.crits() as f32,
+          (_, Stat::Kills) => skill.kills.len() as f32,
+          (s, stat) => stat.of(subject(s)),
+        };
+        cmp.test(lhs, *value)
+      }
+      HasEffect { subject: s, effect } => subject(s).effects.has_effect_kind(*effect),
+      Alive(s) => subject(s).alive,
+      TargetCrit => skill.crit(ctx.target_id),
+      SkillCrit => skill.crits() > 0,
+      SkillKill => !skill.kills.is_empty(),
+    }
+  }
+
+  pub fn parse(text: &str) -> Result<Self, String> {
+    let tokens = tokenize(text)?;
+    let mut parser = Parser { tokens: &tokens, pos: 0 };
+    let cond = parser.or()?;
+    match parser.peek() {
+      None => Ok(cond),
+      Some(tok) => Err(format!("unexpected `{}`", tok)),
+    }
+  }
+}
+
+impl Stat {
+  fn of(&self, hero: &Hero) -> f32 {
+    let stats = &hero.stats;
+    match self {
+      Stat::HpPercent => stats.hp / stats.max_hp * 100.0,
+      Stat::Atk => stats.atk,
+      Stat::Def => stats.def,
+      Stat::Spd => stats.spd,
+      Stat::Cc => stats.cc,
+      Stat::Eff => stats.eff,
+      Stat::EffRes => stats.effres,
+      Stat::Buffs => hero.effects.count(Polarity::Buff) as f32,
+      Stat::Debuffs => hero.effects.count(Polarity::Debuff) as f32,
+      Stat::Damage | Stat::Hits | Stat::Crits | Stat::Kills => 0.0,
+    }
+  }
+
+  fn applies_to(&self, subject: Subject) -> bool {
+    let skill_stat = matches!(self, Stat::Damage | Stat::Hits | Stat::Crits | Stat::Kills);
+    match subject {
+      Subject::Caster => !skill_stat,
+      Subject::Target => !skill_stat || *self == Stat::Damage,
+      Subject::Skill => skill_stat,
+    }
+  }
+
+  fn name(&self) -> &'static str {
+    match self {
+      Stat::HpPercent => "hp",
+      Stat::Atk => "atk",
+      Stat::Def => "def",
+      Stat::Spd => "spd",
+      Stat::Cc => "cc",
+      Stat::Eff => "eff",
+      Stat::EffRes => "effres",
+      Stat::Buffs => "buffs",
+      Stat::Debuffs => "debuffs",
+      Stat::Damage => "damage",
+      Stat::Hits => "hits",
+      Stat::Crits => "crits",
+      Stat::Kills => "kills",
+    }
+  }
+
+  fn from_name(name: &str) -> Option<Self> {
+    [
+      Stat::HpPercent, Stat::Atk, Stat::Def, Stat::Spd, Stat::Cc, Stat::Eff, Stat::EffRes, Stat::Buffs, Stat::Debuffs,
+      Stat::Damage, Stat::Hits, Stat::Crits, Stat::Kills,
+    ]
+      .iter()
+      .copied()
+      .find(|x| x.name() == name)
+  }
+}
+
+impl Cmp {
+  fn test(&self, lhs: f32, rhs: f32) -> bool {
+    match self {
+      Cmp::Lt => lhs < rhs,
+      Cmp::Le => lhs <= rhs,
+      Cmp::Gt => lhs > rhs,
+      Cmp::Ge => lhs >= rhs,
+      Cmp::Eq => (lhs - rhs).abs() < f32::EPSILON,
+      Cmp::Ne => (lhs - rhs).abs() >= f32::EPSILON,
+    }
+  }
+
+  fn symbol(&self) -> &'static str {
+    match self {
+      Cmp::Lt => "<",
+      Cmp::Le => "<=",
+      Cmp::Gt => ">",
+      Cmp::Ge => ">=",
+      Cmp::Eq => "==",
+      Cmp::Ne => "!=",
+    }
+  }
+}
+
+impl Subject {
+  fn name(&self) -> &'static str {
+    match self {
+      Subject::Caster => "caster",
+      Subject::Target => "target",
+      Subject::Skill => "skill",
+    }
+  }
+}
+
+// Effects are named as in data files. Effects carrying a value (bleed, burn)
+// match on kind only, so their value is left at 0.
+pub fn effect_from_name(name: &str) -> Option<Effect> {
+  serde_json::from_value(serde_json::Value::String(name.to_string()))
+    .or_else(|_| serde_json::from_value(serde_json::json!({ name: 0.0 })))
+    .ok()
+}
+
+pub fn effect_name(effect: Effect) -> String {
+  match serde_json::to_value(effect) {
+    Ok(serde_json::Value::String(name)) => name,
+    Ok(serde_json::Value::Object(map)) => map.keys().next().cloned().unwrap_or_default(),
+    _ => format!("{:?}", effect),
+  }
+}
+
+impl fmt::Display for Condition {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    use Condition::*;
+
+    // Nested binary operators are parenthesised so the output parses back
+    // the same regardless of precedence.
+    let operand = |c: &Condition, f: &mut fmt::Formatter| match c {
+      And(..) | Or(..) => write!(f, "({})", c),
+      _ => write!(f, "{}", c),
+    };
+
+    match self {
+      Not(c) => {
+        write!(f, "not ")?;
+        operand(c, f)
+      }
+      And(a, b) | Or(a, b) => {
+        operand(a, f)?;
+        write!(f, " {} ", if matches!(self, And(..)) { "and" } else { "or" })?;
+        operand(b, f)
+      }
+      Compare { subject, stat, cmp, value } => {
+        let unit = if matches!(stat, Stat::HpPercent) { "%" } else { "" };
+        write!(f, "{}.{} {} {}{}", subject.name(), stat.name(), cmp.symbol(), value, unit)
+      }
+      HasEffect { subject, effect } => write!(f, "{}.has({})", subject.name(), effect_name(*effect)),
+      Alive(subject) => write!(f, "{}.alive", subject.name()),
+      TargetCrit => write!(f, "target.crit"),
+      SkillCrit => write!(f, "skill.crit"),
+      SkillKill => write!(f, "skill.kill"),
+    }
+  }
+}
+
+impl TryFrom<String> for Condition {
+  type Error = String;
+
+  fn try_from(text: String) -> Result<Self, String> {
+    Condition::parse(&text).map_err(|e| format!("invalid condition `{}`: {}", text, e))
+  }
+}
+
+impl From<Condition> for String {
+  fn from(c: Condition) -> String {
+    c.to_string()
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+  Ident(String),
+  Number(f32),
+  Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Token::Ident(x) => write!(f, "{}", x),
+      Token::Number(x) => write!(f, "{}", x),
+      Token::Symbol(x) => write!(f, "{}", x),
+    }
+  }
+}
+
+const SYMBOLS: [&str; 10] = ["<=", ">=", "==", "!=", "<", ">", "(", ")", ".", "%"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+  let mut tokens = vec![];
+  let mut rest = text.trim_start();
+
+  while let Some(c) = rest.chars().next() {
+    if c.is_ascii_alphabetic() || c == '_' {
+      let end = rest.find(|x: char| !(x.is_ascii_alphanumeric() || x == '_')).unwrap_or(rest.len());
+      tokens.push(Token::Ident(rest[..end].to_string()));
+      rest = &rest[end..];
+    } else if c.is_ascii_digit() {
+      let end = rest.find(|x: char| !(x.is_ascii_digit() || x == '.')).unwrap_or(rest.len());
+      let value = rest[..end].parse().map_err(|_| format!("bad number `{}`", &rest[..end]))?;
+      tokens.push(Token::Number(value));
+      rest = &rest[end..];
+    } else if let Some(&sym) = SYMBOLS.iter().find(|&&x| rest.starts_with(x)) {
+      tokens.push(Token::Symbol(sym));
+      rest = &rest[sym.len()..];
+    } else {
+      return Err(format!("unexpected `{}`", c));
+    }
+    rest = rest.trim_start();
+  }
+  Ok(tokens)
+}
+
+struct Parser<'a> {
+  tokens: &'a [Token],
+  pos: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn peek(&self) -> Option<&'a Token> {
+    self.tokens.get(self.pos)
+  }
+
+  fn next(&mut self) -> Result<&'a Token, String> {
+    let tok = self.tokens.get(self.pos).ok_or_else(|| "unexpected end of condition".to_string())?;
+    self.pos += 1;
+    Ok(tok)
+  }
+
+  fn eat_keyword(&mut self, keyword: &str) -> bool {
+    match self.peek() {
+      Some(Token::Ident(x)) if x == keyword => {
+        self.pos += 1;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  fn expect(&mut self, sym: &str) -> Result<(), String> {
+    match self.next()? {
+      Token::Symbol(x) if *x == sym => Ok(()),
+      tok => Err(format!("expected `{}`, found `{}`", sym, tok)),
+    }
+  }
+
+  fn ident(&mut self) -> Result<&'a str, String> {
+    match self.next()? {
+      Token::Ident(x) => Ok(x),
+      tok => Err(format!("expected a name, found `{}`", tok)),
+    }
+  }
+
+  fn or(&mut self) -> Result<Condition, String> {
+    let mut cond = self.and()?;
+    while self.eat_keyword("or") {
+      cond = Condition::Or(Box::new(cond), Box::new(self.and()?));
+    }
+    Ok(cond)
+  }
+
+  fn and(&mut self) -> Result<Condition, String> {
+    let mut cond = self.unary()?;
+    while self.eat_keyword("and") {
+      cond = Condition::And(Box::new(cond), Box::new(self.unary()?));
+    }
+    Ok(cond)
+  }
+
+  fn unary(&mut self) -> Result<Condition, String> {
+    if self.eat_keyword("not") {
+      return Ok(Condition::Not(Box::new(self.unary()?)));
+    }
+    if let Some(Token::Symbol("(")) = self.peek() {
+      self.pos += 1;
+      let cond = self.or()?;
+      self.expect(")")?;
+      return Ok(cond);
+    }
+    self.predicate()
+  }
+
+  fn predicate(&mut self) -> Result<Condition, String> {
+    let subject = match self.ident()? {
+      "caster" => Subject::Caster,
+      "target" => Subject::Target,
+      "skill" => Subject::Skill,
+      x => return Err(format!("expected `caster`, `target` or `skill`, found `{}`", x)),
+    };
+    self.expect(".")?;
+    let hero = subject != Subject::Skill;
+
+    match self.ident()? {
+      "alive" if hero => Ok(Condition::Alive(subject)),
+      "crit" if subject == Subject::Target => Ok(Condition::TargetCrit),
+      "crit" if subject == Subject::Skill => Ok(Condition::SkillCrit),
+      "kill" if subject == Subject::Skill => Ok(Condition::SkillKill),
+      "has" if hero => {
+        self.expect("(")?;
+        let name = self.ident()?;
+        let effect = effect_from_name(name).ok_or_else(|| format!("unknown effect `{}`", name))?;
+        self.expect(")")?;
+        Ok(Condition::HasEffect { subject, effect })
+      }
+      name => {
+        let stat = Stat::from_name(name)
+          .filter(|x| x.applies_to(subject))
+          .ok_or_else(|| format!("unknown property `{}.{}`", subject.name(), name))?;
+        let cmp = match self.next()? {
+          Token::Symbol("<") => Cmp::Lt,
+          Token::Symbol("<=") => Cmp::Le,
+          Token::Symbol(">") => Cmp::Gt,
+          Token::Symbol(">=") => Cmp::Ge,
+          Token::Symbol("==") => Cmp::Eq,
+          Token::Symbol("!=") => Cmp::Ne,
+          tok => return Err(format!("expected a comparison, found `{}`", tok)),
+        };
+        let value = match self.next()? {
+          Token::Number(x) => *x,
+          tok => return Err(format!("expected a number, found `{}`", tok)),
+        };
+        if let Some(Token::Symbol("%")) = self.peek() {
+          self.pos += 1;
+        }
+        Ok(Condition::Compare { subject, stat, cmp, value })
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::battle::{ test_hero, DotAmount };
+
+  #[test]
+  fn test_parse_round_trip() {
+    let cond = Condition::parse("target.hp < 50% and not (target.has(immunity) or caster.has(bleed))").unwrap();
+    assert_eq!(
+      cond,
+      Condition::And(
+        Box::new(Condition::Compare { subject: Subject::Target, stat: Stat::HpPercent, cmp: Cmp::Lt, value: 50.0 }),
+        Box::new(Condition::Not(Box::new(Condition::Or(
+          Box::new(Condition::HasEffect { subject: Subject::Target, effect: Effect::Immunity }),
+          Box::new(Condition::HasEffect { subject: Subject::Caster, effect: Effect::Bleed(DotAmount(0.0)) }),
+        )))),
+      )
+    );
+    assert_eq!(Condition::parse(&cond.to_string()).unwrap(), cond);
+
+    let cond = Condition::parse("target.crit or (skill.kill and not skill.crit)").unwrap();
+    assert_eq!(Condition::parse(&cond.to_string()).unwrap(), cond);
+
+    let cond = Condition::parse("skill.damage >= 5000 and target.damage > 0 or skill.kills == 2").unwrap();
+    assert_eq!(Condition::parse(&cond.to_string()).unwrap(), cond);
+
+    let cond = Condition::parse("target.debuffs > 0 and caster.buffs >= 2").unwrap();
+    assert_eq!(
+      cond,
+      Condition::And(
+        Box::new(Condition::Compare { subject: Subject::Target, stat: Stat::Debuffs, cmp: Cmp::Gt, value: 0.0 }),
+        Box::new(Condition::Compare { subject: Subject::Caster, stat: Stat::Buffs, cmp: Cmp::Ge, value: 2.0 }),
+      )
+    );
+    assert_eq!(Condition::parse(&cond.to_string()).unwrap(), cond);
+
+    assert!(Condition::parse("skill.debuffs > 0").is_err());
+    assert!(Condition::parse("caster.crit").is_err());
+    assert!(Condition::parse("caster.damage > 0").is_err());
+    assert!(Condition::parse("skill.hp > 0").is_err());
+    assert!(Condition::parse("target.hp <").is_err());
+    assert!(Condition::parse("enemy.alive").is_err());
+    assert!(Condition::parse("target.has(nothing)").is_err());
+  }
+
+  #[test]
+  fn test_eval_effect_counts() {
+    let (mut caster, mut target) = (test_hero(1), test_hero(2));
+    let skill_use = SkillUse::new(0);
+    let cond = Condition::parse("target.debuffs > 0 and caster.buffs < 1").unwrap();
+    let eval = |caster: &Hero, target: &Hero| cond.eval(&ConditionContext { caster, target, target_id: 1, skill_use: &skill_use });
+
+    assert!(!eval(&caster, &target));
+    // Buffs on the target don't count as debuffs.
+    target.apply_effect(Effect::AtkBuff, 2, None);
+    assert!(!eval(&caster, &target));
+    target.apply_effect(Effect::Bleed(DotAmount(100.0)), 2, Some(0));
+    assert!(eval(&caster, &target));
+    caster.apply_effect(Effect::DefBuff, 2, None);
+    assert!(!eval(&caster, &target));
+  }
+}
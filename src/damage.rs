@@ -0,0 +1,145 @@
+use crate::{
+  battle::{ use_skill, BattleSnapshot, Effect, Hero },
+  combat_log::{ BattleEvent, HitKind },
+  skill::Skill
+};
+use serde::Serialize;
+
+const ATTACKER: usize = 0;
+const DEFENDER: usize = 1;
+
+// One distinct way a skill can play out against a single defender.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DamageOutcome {
+  pub damage: f32,
+  pub probability: f32,
+  pub hits: Vec<HitKind>,
+  pub procs: Vec<Effect>,
+  pub kill: bool,
+}
+
+#[derive(Serialize)]
+pub struct DamageDistribution {
+  // Sorted by damage, lowest first.
+  pub outcomes: Vec<DamageOutcome>,
+}
+
+impl DamageDistribution {
+  pub fn kill_chance(&self) -> f32 {
+    self.outcomes.iter().filter(|x| x.kill).fold(0.0, |acc, x| acc + x.probability)
+  }
+
+  pub fn expected_damage(&self) -> f32 {
+    self.outcomes.iter().fold(0.0, |acc, x| acc + x.damage * x.probability)
+  }
+
+  // Probability of dealing at least `damage`.
+  pub fn chance_at_least(&self, damage: f32) -> f32 {
+    self.outcomes.iter().filter(|x| x.damage >= damage).fold(0.0, |acc, x| acc + x.probability)
+  }
+}
+
+// Exact distribution of the damage `attacker` deals to `defender` with one use
+// of `skill`. Paths that end up with the same damage, hits and procs are merged.
+pub fn damage_distribution(attacker: &Hero, defender: &Hero, skill: &Skill) -> DamageDistribution {
+  let mut attacker = attacker.clone();
+  let mut defender = defender.clone();
+  attacker.team = 1;
+  defender.team = 2;
+  let ss = BattleSnapshot::new(vec![attacker, defender]);
+
+  let mut outcomes: Vec<DamageOutcome> = vec![];
+  use_skill(skill, ATTACKER).expand(ss, &mut |ss, probability| {
+    let mut outcome = DamageOutcome {
+      damage: 0.0,
+      probability,
+      hits: vec![],
+      procs: vec![],
+      kill: !ss.heroes[DEFENDER].alive,
+    };
+    for event in ss.log.events() {
+      match *event {
+        BattleEvent::Attack { target: DEFENDER, hit, .. } => outcome.hits.push(hit),
+        BattleEvent::Damage { src: Some(ATTACKER), target: DEFENDER, amount } => outcome.damage += amount,
+        BattleEvent::EffectApplied { target: DEFENDER, effect, .. } => outcome.procs.push(effect),
+        _ => (),
+      }
+    }
+
+    let same = outcomes.iter_mut().find(|x| {
+      x.damage.round() == outcome.damage.round() && x.hits == outcome.hits && x.procs == outcome.procs
+    });
+    match same {
+      Some(same) => same.probability += probability,
+      None => outcomes.push(outcome),
+    }
+  });
+
+  outcomes.sort_by(|x, y| x.damage.partial_cmp(&y.damage).unwrap());
+  DamageDistribution { outcomes }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::battle::*;
+  use crate::skill::*;
+
+  fn hero(cc: f32, hp: f32) -> Hero {
+    let mut hero = test_hero(0);
+    hero.stats = HeroStats { max_hp: hp, hp, def: 300.0, cc, cdmg: 200.0, ..test_stats() };
+    hero
+  }
+
+  #[test]
+  fn test_crit_distribution() {
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage());
+
+    // 1000 raw against 300 DEF is 500 damage, 1000 on crit.
+    let dist = damage_distribution(&hero(25.0, 1000.0), &hero(0.0, 800.0), &skill);
+    assert_eq!(dist.outcomes.len(), 2);
+    assert_eq!(dist.outcomes[0].damage, 500.0);
+    assert_eq!(dist.outcomes[0].hits, vec![HitKind::Normal]);
+    assert_eq!(dist.outcomes[1].damage, 1000.0);
+    assert_eq!(dist.outcomes[1].hits, vec![HitKind::Crit]);
+    assert_eq!(dist.kill_chance(), 0.25);
+    assert_eq!(dist.expected_damage(), 625.0);
+    assert_eq!(dist.chance_at_least(500.0), 1.0);
+    assert_eq!(dist.chance_at_least(501.0), 0.25);
+    assert_eq!(dist.chance_at_least(1001.0), 0.0);
+  }
+
+  #[test]
+  fn test_condition_checks_target() {
+    use crate::condition::Condition;
+
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage());
+    skill.new_component(SkillAction::damage().pow(0.5))
+      .activate_condition(Condition::parse("target.hp < 50% and not target.crit").unwrap());
+
+    // A normal first hit leaves the defender at 400/900 HP, a crit kills.
+    let dist = damage_distribution(&hero(25.0, 1000.0), &hero(0.0, 900.0), &skill);
+    assert_eq!(dist.outcomes[0].hits, vec![HitKind::Normal, HitKind::Normal]);
+    assert_eq!(dist.outcomes[0].damage, 500.0 + 250.0);
+    assert_eq!(dist.outcomes[0].probability, 0.75 * 0.75);
+  }
+
+  #[test]
+  fn test_component_reads_skill_use() {
+    use crate::condition::Condition;
+
+    let mut skill = Skill::new("S1");
+    skill.new_component(SkillAction::damage());
+    skill.new_component(SkillAction::damage().pow(0.5))
+      .targeting(Targeting::Hit)
+      .activate_condition(Condition::parse("skill.crits >= 1").unwrap());
+
+    // Only a crit on the first hit earns the follow-up on the same target.
+    let dist = damage_distribution(&hero(25.0, 1000.0), &hero(0.0, 5000.0), &skill);
+    assert_eq!(dist.outcomes[0].hits, vec![HitKind::Normal]);
+    assert_eq!(dist.outcomes[1].hits, vec![HitKind::Crit, HitKind::Normal]);
+    assert_eq!(dist.outcomes[1].damage, 1000.0 + 250.0);
+  }
+}
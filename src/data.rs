@@ -0,0 +1,610 @@
+use crate::{
+  battle::*,
+  skill::*,
+  gear::{ self, Gear },
+  artifact::Artifact
+};
+use serde::{ Serialize, Deserialize, Deserializer, de::{ self, DeserializeOwned } };
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// Hero and team definitions as written in a data file. Field names follow
+// the file format (snake_case); `Roster::build` turns them into battle types.
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Roster {
+  #[serde(default)]
+  pub teams: Vec<TeamDef>,
+  // Spare pieces for `optimizer` to pick from.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub gear: Vec<Gear>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TeamDef {
+  pub name: String,
+  pub heroes: Vec<HeroDef>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeroDef {
+  pub name: String,
+  pub element: Element,
+  pub stats: StatsDef,
+  #[serde(default, skip_serializing_if = "is_zero")]
+  pub readiness: u32,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub effects: Vec<EffectDef>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub skills: Vec<Skill>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub passives: Vec<Passive>,
+  // Worn on top of `stats`, at most one piece per slot.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub gear: Vec<Gear>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub artifact: Option<Artifact>,
+}
+
+// Optional stats default to a fresh, ungeared hero. `hp` defaults to `max_hp`;
+// a hero saved at 0 HP or below loads as dead.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsDef {
+  pub max_hp: f32,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub hp: Option<f32>,
+  pub atk: f32,
+  pub def: f32,
+  pub spd: f32,
+  #[serde(default = "default_cc")]
+  pub cc: f32,
+  #[serde(default = "default_cdmg")]
+  pub cdmg: f32,
+  #[serde(default)]
+  pub eff: f32,
+  #[serde(default)]
+  pub effres: f32,
+  #[serde(default = "default_hit_chance")]
+  pub hit_chance: f32,
+  #[serde(default)]
+  pub crit_resist: f32,
+  #[serde(default)]
+  pub counter: f32,
+  #[serde(default)]
+  pub lifesteal: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EffectDef {
+  #[serde(deserialize_with = "enum_from_table")]
+  pub effect: Effect,
+  pub duration: u32,
+}
+
+// The toml reader only takes enum variants with data, like
+// `trigger = { hp_below = 50 }`, from inline tables, while `to_string` writes
+// them as tables of their own. Going through a JSON value reads either.
+pub fn enum_from_table<'de, D: Deserializer<'de>, T: DeserializeOwned>(deserializer: D) -> Result<T, D::Error> {
+  let value = serde_json::Value::deserialize(deserializer)?;
+  T::deserialize(value).map_err(de::Error::custom)
+}
+
+fn is_zero(x: &u32) -> bool { *x == 0 }
+fn default_cc() -> f32 { 15.0 }
+fn default_cdmg() -> f32 { 150.0 }
+fn default_hit_chance() -> f32 { 100.0 }
+
+#[derive(Debug)]
+pub enum DataError {
+  Io(std::io::Error),
+  // Syntax or type error reported by the parser, with its location.
+  Parse(String),
+  // Roster the chosen format can't represent.
+  Serialize(String),
+  // Well-formed file with a value that makes no sense, e.g.
+  // `teams[0].heroes[1].stats.hp`.
+  Invalid { field: String, reason: String },
+  UnknownFormat(String),
+}
+
+impl fmt::Display for DataError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DataError::Io(e) => write!(f, "{}", e),
+      DataError::Parse(e) => write!(f, "parse error: {}", e),
+      DataError::Serialize(e) => write!(f, "serialize error: {}", e),
+      DataError::Invalid { field, reason } => write!(f, "{}: {}", field, reason),
+      DataError::UnknownFormat(path) => write!(f, "{}: expected a .toml or .json file", path),
+    }
+  }
+}
+
+impl std::error::Error for DataError {}
+
+impl From<std::io::Error> for DataError {
+  fn from(e: std::io::Error) -> Self {
+    DataError::Io(e)
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Format {
+  Toml,
+  Json,
+}
+
+impl Format {
+  pub fn from_path(path: &Path) -> Result<Self, DataError> {
+    match path.extension().and_then(|x| x.to_str()) {
+      Some("toml") => Ok(Format::Toml),
+      Some("json") => Ok(Format::Json),
+      _ => Err(DataError::UnknownFormat(path.display().to_string())),
+    }
+  }
+}
+
+fn invalid(field: String, reason: &str) -> DataError {
+  DataError::Invalid { field, reason: reason.to_string() }
+}
+
+impl Roster {
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, DataError> {
+    let path = path.as_ref();
+    let format = Format::from_path(path)?;
+    Roster::parse(&fs::read_to_string(path)?, format)
+  }
+
+  pub fn parse(text: &str, format: Format) -> Result<Self, DataError> {
+    let roster: Roster = match format {
+      Format::Toml => toml::from_str(text).map_err(|e| DataError::Parse(e.to_string()))?,
+      Format::Json => serde_json::from_str(text).map_err(|e| DataError::Parse(e.to_string()))?,
+    };
+    roster.validate()?;
+    Ok(roster)
+  }
+
+  pub fn save(&self, path: impl AsRef<Path>) -> Result<(), DataError> {
+    let path = path.as_ref();
+    let text = self.to_string(Format::from_path(path)?)?;
+    fs::write(path, text)?;
+    Ok(())
+  }
+
+  pub fn to_string(&self, format: Format) -> Result<String, DataError> {
+    match format {
+      // The toml writer can't write enum variants with data, like
+      // `hp_below = 50`, and wants plain values ahead of tables. A JSON value
+      // writes those variants as tables, and a TOML value orders its keys.
+      Format::Toml => serde_json::to_value(self)
+        .map_err(|e| DataError::Serialize(e.to_string()))
+        .and_then(|x| toml::Value::try_from(x).map_err(|e| DataError::Serialize(e.to_string())))
+        .and_then(|x| toml::to_string_pretty(&x).map_err(|e| DataError::Serialize(e.to_string()))),
+      Format::Json => serde_json::to_string_pretty(self).map_err(|e| DataError::Serialize(e.to_string())),
+    }
+  }
+
+  pub fn validate(&self) -> Result<(), DataError> {
+    for (t, team) in self.teams.iter().enumerate() {
+      let path = format!("teams[{}]", t);
+      if team.heroes.is_empty() {
+        return Err(invalid(format!("{}.heroes", path), "team has no heroes"));
+      }
+      for (h, hero) in team.heroes.iter().enumerate() {
+        hero.validate(&format!("{}.heroes[{}]", path, h))?;
+      }
+    }
+    for (i, piece) in self.gear.iter().enumerate() {
+      piece.check().map_err(|(name, reason)| invalid(format!("gear[{}].{}", i, name), reason))?;
+    }
+    Ok(())
+  }
+
+  pub fn team(&self, name: &str) -> Option<&TeamDef> {
+    self.teams.iter().find(|x| x.name == name)
+  }
+
+  // Heroes of every team in order, team numbers starting at 1, along with
+  // each hero's skills.
+  pub fn build(&self) -> (BattleSnapshot, Vec<Vec<Skill>>) {
+    let mut heroes = vec![];
+    let mut skills = vec![];
+    for (t, team) in self.teams.iter().enumerate() {
+      for hero in &team.heroes {
+        heroes.push(hero.to_hero(t as u32 + 1));
+        skills.push(hero.skills.clone());
+      }
+    }
+    (BattleSnapshot::new(heroes), skills)
+  }
+
+  // Passives of every hero in the same order as `build`.
+  pub fn passives(&self) -> Vec<Vec<Passive>> {
+    self.teams.iter().flat_map(|x| &x.heroes).map(|x| x.passives.clone()).collect()
+  }
+
+  // Picker for the heroes of `build`, with their passives and artifacts.
+  pub fn picker(&self) -> AISkillPicker {
+    let (_, skills) = self.build();
+    let mut picker = AISkillPicker::new(skills).with_passives(self.passives());
+    for (hero, def) in self.teams.iter().flat_map(|x| &x.heroes).enumerate() {
+      if let Some(ref artifact) = def.artifact {
+        picker = picker.with_artifact(hero, artifact);
+      }
+    }
+    picker
+  }
+}
+
+impl HeroDef {
+  fn validate(&self, path: &str) -> Result<(), DataError> {
+    let stats = &self.stats;
+    let field = |name: &str| format!("{}.{}", path, name);
+
+    if self.name.is_empty() {
+      return Err(invalid(field("name"), "must not be empty"));
+    }
+    if stats.max_hp <= 0.0 {
+      return Err(invalid(field("stats.max_hp"), "must be greater than 0"));
+    }
+    if let Some(hp) = stats.hp {
+      if hp > stats.max_hp {
+        return Err(invalid(field("stats.hp"), "must be at most max_hp"));
+      }
+    }
+    if stats.spd <= 0.0 {
+      return Err(invalid(field("stats.spd"), "must be greater than 0"));
+    }
+    let non_negative = [
+      ("atk", stats.atk), ("def", stats.def), ("cc", stats.cc), ("cdmg", stats.cdmg),
+      ("eff", stats.eff), ("effres", stats.effres), ("hit_chance", stats.hit_chance),
+      ("crit_resist", stats.crit_resist), ("counter", stats.counter), ("lifesteal", stats.lifesteal),
+    ];
+    for &(name, value) in &non_negative {
+      if value < 0.0 {
+        return Err(invalid(field(&format!("stats.{}", name)), "must not be negative"));
+      }
+    }
+    for (i, effect) in self.effects.iter().enumerate() {
+      if effect.duration == 0 {
+        return Err(invalid(field(&format!("effects[{}].duration", i)), "must be at least 1"));
+      }
+    }
+    for (i, piece) in self.gear.iter().enumerate() {
+      let path = field(&format!("gear[{}]", i));
+      if self.gear[..i].iter().any(|x| x.slot == piece.slot) {
+        return Err(invalid(format!("{}.slot", path), "slot already taken"));
+      }
+      piece.check().map_err(|(name, reason)| invalid(format!("{}.{}", path, name), reason))?;
+    }
+    if let Some(ref artifact) = self.artifact {
+      if artifact.atk < 0.0 || artifact.hp < 0.0 {
+        return Err(invalid(field("artifact"), "stats must not be negative"));
+      }
+      for (i, passive) in artifact.procs.iter().enumerate() {
+        validate_passive(passive, &field(&format!("artifact.procs[{}]", i)))?;
+      }
+    }
+    for (i, skill) in self.skills.iter().enumerate() {
+      validate_skill(skill, &field(&format!("skills[{}]", i)))?;
+    }
+    for (i, passive) in self.passives.iter().enumerate() {
+      validate_passive(passive, &field(&format!("passives[{}]", i)))?;
+    }
+    Ok(())
+  }
+
+  // Stats with gear and artifact on, and effects from the file and gear sets
+  // applied.
+  pub fn to_hero(&self, team: u32) -> Hero {
+    let stats = &self.stats;
+    let base = HeroStats {
+      max_hp: stats.max_hp,
+      hp: stats.max_hp,
+      atk: stats.atk,
+      spd: stats.spd,
+      def: stats.def,
+      cc: stats.cc,
+      cdmg: stats.cdmg,
+      eff: stats.eff,
+      effres: stats.effres,
+      element: self.element,
+      hit_chance: stats.hit_chance,
+      crit_resist: stats.crit_resist,
+      counter: stats.counter,
+      lifesteal: stats.lifesteal,
+    };
+    let mut stats = gear::compile(&base, &self.gear);
+    if let Some(ref artifact) = self.artifact {
+      stats = artifact.apply(&stats);
+    }
+    stats.hp = self.stats.hp.unwrap_or(stats.max_hp);
+    let mut hero = Hero {
+      name: self.name.clone(),
+      alive: stats.hp > 0.0,
+      base_stats: stats.clone(),
+      stats,
+      readiness: self.readiness,
+      effects: Effects::new(),
+      team,
+      cooldowns: vec![],
+    };
+    for effect in &self.effects {
+      hero.apply_effect(effect.effect, effect.duration, None);
+    }
+    for (effect, duration) in gear::starting_effects(&self.gear) {
+      hero.apply_effect(effect, duration, None);
+    }
+    hero
+  }
+}
+
+fn validate_skill(skill: &Skill, path: &str) -> Result<(), DataError> {
+  if let Some(ref dual) = skill.dual_atk {
+    if !(0.0..=1.0).contains(&dual.chance) {
+      return Err(invalid(format!("{}.dual_atk.chance", path), "must be between 0 and 1"));
+    }
+  }
+  validate_components(&skill.components, path)
+}
+
+fn validate_passive(passive: &Passive, path: &str) -> Result<(), DataError> {
+  if !(0.0..=1.0).contains(&passive.chance) {
+    return Err(invalid(format!("{}.chance", path), "must be between 0 and 1"));
+  }
+  if let Trigger::HpBelow(pct) = passive.trigger {
+    if !(0.0..=100.0).contains(&pct) {
+      return Err(invalid(format!("{}.trigger.hp_below", path), "must be between 0 and 100"));
+    }
+  }
+  validate_components(&passive.components, path)
+}
+
+fn validate_components(components: &[SkillComponent], path: &str) -> Result<(), DataError> {
+  if components.is_empty() {
+    return Err(invalid(format!("{}.components", path), "skill does nothing"));
+  }
+  for (i, comp) in components.iter().enumerate() {
+    let field = format!("{}.components[{}].action", path, i);
+    match comp.action {
+      SkillAction::Effect { chance, duration, .. } => {
+        if !(0.0..=1.0).contains(&chance) {
+          return Err(invalid(format!("{}.chance", field), "must be between 0 and 1"));
+        }
+        if duration == 0 {
+          return Err(invalid(format!("{}.duration", field), "must be at least 1"));
+        }
+      }
+      SkillAction::Damage(ref dmg) if !(0.0..=1.0).contains(&dmg.def_pen) => {
+        return Err(invalid(format!("{}.def_pen", field), "must be between 0 and 1"));
+      }
+      SkillAction::Damage(ref dmg) if dmg.lifesteal < 0.0 => {
+        return Err(invalid(format!("{}.lifesteal", field), "must not be negative"));
+      }
+      SkillAction::Damage(ref dmg) if dmg.fixed < 0.0 => {
+        return Err(invalid(format!("{}.fixed", field), "must not be negative"));
+      }
+      SkillAction::Heal { rate, .. } if rate < 0.0 => {
+        return Err(invalid(format!("{}.rate", field), "must not be negative"));
+      }
+      SkillAction::Shield { rate, duration, .. } => {
+        if rate < 0.0 {
+          return Err(invalid(format!("{}.rate", field), "must not be negative"));
+        }
+        if duration == 0 {
+          return Err(invalid(format!("{}.duration", field), "must be at least 1"));
+        }
+      }
+      SkillAction::Revive { rate } if !(rate > 0.0 && rate <= 1.0) => {
+        return Err(invalid(format!("{}.rate", field), "must be above 0 and at most 1"));
+      }
+      SkillAction::ChangeCr { amount } if !(-1.0..=1.0).contains(&amount) => {
+        return Err(invalid(format!("{}.amount", field), "must be between -1 and 1"));
+      }
+      SkillAction::Splash { def_pen, .. } if !(0.0..=1.0).contains(&def_pen) => {
+        return Err(invalid(format!("{}.def_pen", field), "must be between 0 and 1"));
+      }
+      SkillAction::Splash { atk_rate, .. } if atk_rate < 0.0 => {
+        return Err(invalid(format!("{}.atk_rate", field), "must not be negative"));
+      }
+      SkillAction::DispelBuffs { count: 0 } | SkillAction::CleanseDebuffs { count: 0 } => {
+        return Err(invalid(format!("{}.count", field), "must be at least 1"));
+      }
+      _ => (),
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::condition::Condition;
+
+  const ROSTER: &str = r#"
+    [[teams]]
+    name = "Ally"
+
+    [[teams.heroes]]
+    name = "Solitaria"
+    element = "light"
+    stats = { max_hp = 10000, atk = 3000, def = 1000, spd = 200 }
+
+    [[teams.heroes.skills]]
+    name = "S1"
+    components = [
+      { targeting = "enemy_aoe", action = { type = "damage", atk_rate = 0.8 } },
+      { targeting = "enemy_aoe", action = { type = "effect", effect = "atk_down", chance = 0.25, duration = 1 } },
+      { condition = "target.hp < 50%", action = { type = "damage", pow = 0.5 } },
+    ]
+
+    [[teams.heroes.passives]]
+    name = "Retaliate"
+    trigger = { hp_below = 50 }
+    chance = 0.5
+    components = [{ targeting = "trigger", action = { type = "damage", atk_rate = 0.5 } }]
+  "#;
+
+  #[test]
+  fn test_toml_round_trip() {
+    let mut roster = Roster::parse(ROSTER, Format::Toml).unwrap();
+    roster.teams[0].heroes[0].effects.push(EffectDef { effect: Effect::Bleed(DotAmount(300.0)), duration: 2 });
+    roster.teams[0].heroes[0].effects.push(EffectDef { effect: Effect::AtkBuff, duration: 2 });
+    let saved = roster.to_string(Format::Toml).unwrap();
+    let reloaded = Roster::parse(&saved, Format::Toml).unwrap();
+    assert_eq!(reloaded.to_string(Format::Toml).unwrap(), saved);
+
+    let hero = &reloaded.teams[0].heroes[0];
+    assert_eq!(hero.stats.cc, 15.0);
+    assert_eq!(hero.skills[0].components.len(), 3);
+    assert_eq!(hero.passives[0].chance, 0.5);
+    assert_eq!(hero.effects[0].effect, Effect::Bleed(DotAmount(300.0)));
+    // The buff is saved once, on top of the unbuffed stat.
+    assert_eq!(hero.stats.atk, 3000.0);
+    assert_eq!(reloaded.build().0.heroes[0].stats.atk, 4500.0);
+
+    let bad = ROSTER.replace("hp_below = 50", "hp_over = 50");
+    match Roster::parse(&bad, Format::Toml) {
+      Err(DataError::Parse(e)) => assert!(e.contains("unknown variant `hp_over`"), "{}", e),
+      _ => panic!("expected a parse error"),
+    }
+  }
+
+  #[test]
+  fn test_load_and_build() {
+    let roster = Roster::parse(ROSTER, Format::Toml).unwrap();
+    let (ss, skills) = roster.build();
+    assert_eq!(ss.heroes[0].name, "Solitaria");
+    assert_eq!(ss.heroes[0].stats.hp, 10000.0);
+    assert_eq!(ss.heroes[0].stats.cc, 15.0);
+    assert_eq!(skills[0][0].components.len(), 3);
+    assert_eq!(skills[0][0].components[2].activate_condition, Some(Condition::parse("target.hp < 50").unwrap()));
+    assert_eq!(
+      skills[0][0].components[0].action,
+      SkillAction::Damage(DamageAction { atk_rate: 0.8, ..Default::default() })
+    );
+
+    let passives = roster.passives();
+    assert_eq!(passives[0][0].trigger, Trigger::HpBelow(50.0));
+    assert_eq!(passives[0][0].components[0].targeting, Targeting::Trigger);
+  }
+
+  #[test]
+  fn test_validation_points_at_field() {
+    let bad = ROSTER.replace("target.hp < 50%", "target.hp <");
+    match Roster::parse(&bad, Format::Toml) {
+      Err(DataError::Parse(e)) => assert!(e.contains("invalid condition"), "{}", e),
+      _ => panic!("expected a parse error"),
+    }
+
+    let bad = ROSTER.replace("chance = 0.25", "chance = 25.0");
+    match Roster::parse(&bad, Format::Toml) {
+      Err(DataError::Invalid { field, .. }) => {
+        assert_eq!(field, "teams[0].heroes[0].skills[0].components[1].action.chance")
+      }
+      _ => panic!("expected a validation error"),
+    }
+
+    let bad = ROSTER.replace("type = \"damage\", pow = 0.5", "type = \"splash\", atk_rate = 0.5, def_pen = 2.0");
+    match Roster::parse(&bad, Format::Toml) {
+      Err(DataError::Invalid { field, .. }) => {
+        assert_eq!(field, "teams[0].heroes[0].skills[0].components[2].action.def_pen")
+      }
+      _ => panic!("expected a validation error"),
+    }
+
+    let bad = ROSTER.replace("type = \"damage\", pow = 0.5", "type = \"none\"");
+    match Roster::parse(&bad, Format::Toml) {
+      Err(DataError::Parse(e)) => assert!(e.contains("unknown variant"), "{}", e),
+      _ => panic!("expected a parse error"),
+    }
+
+    let bad = ROSTER.replace("atk = 3000", "atk = \"high\"");
+    match Roster::parse(&bad, Format::Toml) {
+      Err(DataError::Parse(e)) => assert!(e.contains("teams.heroes.stats") && e.contains("line 8"), "{}", e),
+      _ => panic!("expected a parse error"),
+    }
+  }
+
+  #[test]
+  fn test_dot_in_data_file_is_atk_rate() {
+    let bleeding = ROSTER.replace("effect = \"atk_down\", chance = 0.25", "effect = { bleed = 0.25 }, chance = 1.0");
+    let roster = Roster::parse(&format!("{}{}", bleeding, r#"
+      [[teams]]
+      name = "Enemy"
+
+      [[teams.heroes]]
+      name = "Dummy"
+      element = "dark"
+      stats = { max_hp = 10000, atk = 100, def = 0, spd = 100 }
+      skills = [{ name = "S1", components = [{ action = { type = "damage" } }] }]
+    "#), Format::Toml).unwrap();
+    let (ss, skills) = roster.build();
+    assert_eq!(
+      skills[0][0].components[1].action,
+      SkillAction::Effect { chance: 1.0, effect: SkillEffect::Bleed(DotRate(0.25)), duration: 1 }
+    );
+
+    // 0.25 of the applier's 3000 ATK, not 0.25 damage.
+    let mut leaves = vec![];
+    use_skill(&skills[0][0], 0).expand(ss, &mut |ss, _| leaves.push(ss));
+    assert!(leaves[0].heroes[1].effects.entries().any(|x| x == (Effect::Bleed(DotAmount(750.0)), 1)));
+
+    let unknown = ROSTER.replace("effect = \"atk_down\"", "effect = \"atk_up\"");
+    assert!(matches!(Roster::parse(&unknown, Format::Toml), Err(DataError::Parse(_))));
+  }
+
+  #[test]
+  fn test_gear_compiled_into_stats() {
+    let geared = format!("{}{}", ROSTER, r#"
+      [[teams.heroes.gear]]
+      slot = "weapon"
+      set = "immunity"
+      main = { stat = "atk", value = 500 }
+      subs = [{ stat = "spd", value = 4 }]
+
+      [[teams.heroes.gear]]
+      slot = "boots"
+      set = "immunity"
+      main = { stat = "spd", value = 45 }
+    "#);
+    let roster = Roster::parse(&geared, Format::Toml).unwrap();
+    let (ss, _) = roster.build();
+    assert_eq!(ss.heroes[0].stats.atk, 3500.0);
+    assert_eq!(ss.heroes[0].stats.spd, 249.0);
+    assert!(ss.heroes[0].effects.has_effect_kind(Effect::Immunity));
+
+    let bad = geared.replace("slot = \"boots\"", "slot = \"weapon\"");
+    match Roster::parse(&bad, Format::Toml) {
+      Err(DataError::Invalid { field, .. }) => assert_eq!(field, "teams[0].heroes[0].gear[1].slot"),
+      _ => panic!("expected a validation error"),
+    }
+  }
+
+  #[test]
+  fn test_artifact_stats_and_procs() {
+    let armed = format!("{}{}", ROSTER, r#"
+      [teams.heroes.artifact]
+      name = "Rod"
+      atk = 250
+      hp = 500
+      procs = [{ name = "Rod", trigger = "attack", chance = 0.25, components = [
+        { targeting = "trigger", action = { type = "change_cr", amount = -0.25 } },
+      ] }]
+    "#);
+    let roster = Roster::parse(&armed, Format::Toml).unwrap();
+    let (ss, _) = roster.build();
+    assert_eq!(ss.heroes[0].stats.atk, 3250.0);
+    assert_eq!(ss.heroes[0].stats.hp, 10500.0);
+
+    assert_eq!(roster.passives()[0].len(), 1);
+    let picker = roster.picker();
+    assert_eq!(picker.passives(0).len(), 2);
+    assert_eq!(picker.passives(0)[1].trigger, Trigger::Attack);
+  }
+}
@@ -0,0 +1,243 @@
+use crate::battle::{ Effect, HeroStats };
+use serde::{ Serialize, Deserialize };
+
+// Equipment worn on top of a hero's base stats. `compile` folds a full set of
+// pieces and their set bonuses into the `HeroStats` a battle uses.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Slot {
+  Weapon,
+  Helmet,
+  Armor,
+  Necklace,
+  Ring,
+  Boots,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GearStat {
+  Atk,
+  AtkPercent,
+  Hp,
+  HpPercent,
+  Def,
+  DefPercent,
+  Spd,
+  Cc,
+  Cdmg,
+  Eff,
+  Effres,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatLine {
+  pub stat: GearStat,
+  pub value: f32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GearSet {
+  Speed,
+  Attack,
+  Health,
+  Defense,
+  Critical,
+  Destruction,
+  Hit,
+  Resist,
+  // Counters through `HeroStats::counter`.
+  Counter,
+  // Heals a share of all damage dealt through `HeroStats::lifesteal`.
+  Lifesteal,
+  // Immunity until the end of the wearer's first turn.
+  Immunity,
+}
+
+impl GearSet {
+  // Pieces of the set needed for one bonus.
+  pub fn pieces(&self) -> usize {
+    use GearSet::*;
+
+    match self {
+      Speed | Attack | Destruction | Counter | Lifesteal => 4,
+      Health | Defense | Critical | Hit | Resist | Immunity => 2,
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Gear {
+  pub slot: Slot,
+  pub set: GearSet,
+  pub main: StatLine,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub subs: Vec<StatLine>,
+}
+
+const MAX_SUBSTATS: usize = 4;
+
+#[cfg(test)]
+impl Gear {
+  pub fn new(slot: Slot, set: GearSet, main: GearStat, value: f32) -> Self {
+    Gear { slot, set, main: StatLine { stat: main, value }, subs: vec![] }
+  }
+
+  pub fn sub(mut self, stat: GearStat, value: f32) -> Self {
+    self.subs.push(StatLine { stat, value });
+    self
+  }
+}
+
+impl Gear {
+  // Main stat weapons, helmets and armor always roll.
+  fn fixed_main(&self) -> Option<GearStat> {
+    match self.slot {
+      Slot::Weapon => Some(GearStat::Atk),
+      Slot::Helmet => Some(GearStat::Hp),
+      Slot::Armor => Some(GearStat::Def),
+      _ => None,
+    }
+  }
+
+  // What's wrong with the piece, if anything, as the offending field and
+  // the reason.
+  pub fn check(&self) -> Result<(), (&'static str, &'static str)> {
+    if matches!(self.fixed_main(), Some(x) if x != self.main.stat) {
+      return Err(("main.stat", "not available on this slot"));
+    }
+    if self.subs.len() > MAX_SUBSTATS {
+      return Err(("subs", "at most 4 substats"));
+    }
+    if self.subs.iter().any(|x| x.stat == self.main.stat) {
+      return Err(("subs", "can't repeat the main stat"));
+    }
+    if self.lines().any(|x| x.value < 0.0) {
+      return Err(("value", "must not be negative"));
+    }
+    Ok(())
+  }
+
+  fn lines<'a>(&'a self) -> impl Iterator<Item = &'a StatLine> + 'a {
+    std::iter::once(&self.main).chain(&self.subs)
+  }
+}
+
+// Bonuses earned by `gear`, once for every complete set of pieces.
+pub fn active_sets(gear: &[Gear]) -> Vec<GearSet> {
+  let mut sets: Vec<(GearSet, usize)> = vec![];
+  for piece in gear {
+    match sets.iter_mut().find(|(set, _)| *set == piece.set) {
+      Some((_, count)) => *count += 1,
+      None => sets.push((piece.set, 1)),
+    }
+  }
+  sets.into_iter()
+    .flat_map(|(set, count)| (0..count / set.pieces()).map(move |_| set))
+    .collect()
+}
+
+// `base` with `gear` and its set bonuses on top. Percentages are of the base
+// stats, and the hero starts at full HP.
+pub fn compile(base: &HeroStats, gear: &[Gear]) -> HeroStats {
+  let mut stats = base.clone();
+  let mut add = |stat: GearStat, value: f32| {
+    use GearStat::*;
+
+    match stat {
+      Atk => stats.atk += value,
+      AtkPercent => stats.atk += base.atk * value / 100.0,
+      Hp => stats.max_hp += value,
+      HpPercent => stats.max_hp += base.max_hp * value / 100.0,
+      Def => stats.def += value,
+      DefPercent => stats.def += base.def * value / 100.0,
+      Spd => stats.spd += value,
+      Cc => stats.cc += value,
+      Cdmg => stats.cdmg += value,
+      Eff => stats.eff += value,
+      Effres => stats.effres += value,
+    }
+  };
+
+  for line in gear.iter().flat_map(|x| x.lines()) {
+    add(line.stat, line.value);
+  }
+  let mut counter = 0.0;
+  let mut lifesteal = 0.0;
+  for set in active_sets(gear) {
+    match set {
+      GearSet::Speed => add(GearStat::Spd, base.spd * 0.25),
+      GearSet::Attack => add(GearStat::AtkPercent, 45.0),
+      GearSet::Health => add(GearStat::HpPercent, 15.0),
+      GearSet::Defense => add(GearStat::DefPercent, 15.0),
+      GearSet::Critical => add(GearStat::Cc, 12.0),
+      GearSet::Destruction => add(GearStat::Cdmg, 60.0),
+      GearSet::Hit => add(GearStat::Eff, 20.0),
+      GearSet::Resist => add(GearStat::Effres, 20.0),
+      GearSet::Counter => counter += 20.0,
+      GearSet::Lifesteal => lifesteal += 20.0,
+      GearSet::Immunity => (),
+    }
+  }
+  stats.counter += counter;
+  stats.lifesteal += lifesteal;
+  stats.hp = stats.max_hp;
+  stats
+}
+
+// Effects the hero enters battle with because of their sets.
+pub fn starting_effects(gear: &[Gear]) -> Vec<(Effect, u32)> {
+  active_sets(gear)
+    .into_iter()
+    .filter(|&x| x == GearSet::Immunity)
+    .map(|_| (Effect::Immunity, 1))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::battle::test_stats;
+
+  fn base() -> HeroStats {
+    HeroStats { max_hp: 10000.0, hp: 10000.0, def: 500.0, cc: 15.0, ..test_stats() }
+  }
+
+  #[test]
+  fn test_compile_gear_and_sets() {
+    let gear = vec![
+      Gear::new(Slot::Weapon, GearSet::Speed, GearStat::Atk, 500.0).sub(GearStat::Spd, 4.0),
+      Gear::new(Slot::Helmet, GearSet::Speed, GearStat::Hp, 2700.0).sub(GearStat::AtkPercent, 8.0),
+      Gear::new(Slot::Armor, GearSet::Speed, GearStat::Def, 300.0),
+      Gear::new(Slot::Necklace, GearSet::Speed, GearStat::Cdmg, 70.0),
+      Gear::new(Slot::Ring, GearSet::Lifesteal, GearStat::HpPercent, 65.0),
+      Gear::new(Slot::Boots, GearSet::Lifesteal, GearStat::Spd, 45.0),
+    ];
+    assert_eq!(active_sets(&gear), vec![GearSet::Speed]);
+
+    let stats = compile(&base(), &gear);
+    assert_eq!(stats.atk, 1000.0 + 500.0 + 80.0);
+    assert_eq!(stats.max_hp, 10000.0 + 2700.0 + 6500.0);
+    assert_eq!(stats.hp, stats.max_hp);
+    assert_eq!(stats.spd, 100.0 + 4.0 + 45.0 + 25.0);
+    assert_eq!(stats.cdmg, 220.0);
+    assert_eq!(stats.lifesteal, 0.0);
+  }
+
+  #[test]
+  fn test_check_gear() {
+    assert!(Gear::new(Slot::Boots, GearSet::Hit, GearStat::Spd, 45.0).check().is_ok());
+    assert_eq!(
+      Gear::new(Slot::Weapon, GearSet::Hit, GearStat::Spd, 45.0).check(),
+      Err(("main.stat", "not available on this slot"))
+    );
+    assert_eq!(
+      Gear::new(Slot::Boots, GearSet::Hit, GearStat::Spd, 45.0).sub(GearStat::Spd, 4.0).check(),
+      Err(("subs", "can't repeat the main stat"))
+    );
+  }
+}
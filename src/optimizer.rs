@@ -0,0 +1,335 @@
+use crate::{
+  battle::*,
+  data::{ HeroDef, Roster },
+  gear::{ self, Gear, Slot },
+  scheduler,
+  stats::OutcomeStats
+};
+use std::fmt;
+use std::sync::{ Arc, Mutex };
+
+// Searches a pool of gear for the builds that do best in a matchup, scored
+// by the battle simulator itself. Simulating is slow, so the pool is pruned
+// and every combination ranked by a cheap gear score first; only the best
+// `candidates` of those are simulated.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Metric {
+  // Of the geared hero's team.
+  WinRate,
+  DamageDealt,
+  Survival,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stat {
+  Hp,
+  Atk,
+  Def,
+  Spd,
+  Cc,
+  Cdmg,
+  Eff,
+  Effres,
+}
+
+impl Stat {
+  pub fn of(&self, stats: &HeroStats) -> f32 {
+    match self {
+      Stat::Hp => stats.max_hp,
+      Stat::Atk => stats.atk,
+      Stat::Def => stats.def,
+      Stat::Spd => stats.spd,
+      Stat::Cc => stats.cc,
+      Stat::Cdmg => stats.cdmg,
+      Stat::Eff => stats.eff,
+      Stat::Effres => stats.effres,
+    }
+  }
+}
+
+pub struct Build {
+  pub gear: Vec<Gear>,
+  pub stats: HeroStats,
+  pub score: f32,
+}
+
+#[derive(Debug)]
+pub struct NoSuchHero(pub HeroID);
+
+impl fmt::Display for NoSuchHero {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "no hero {} in the roster", self.0)
+  }
+}
+
+impl std::error::Error for NoSuchHero {}
+
+pub struct Optimizer {
+  metric: Metric,
+  // Final stats the build must reach, e.g. SPD to outspeed someone.
+  minimums: Vec<(Stat, f32)>,
+  // Pieces kept for each slot and set after pruning.
+  per_slot: usize,
+  // Builds simulated, best gear score first.
+  candidates: usize,
+  samples: u32,
+  seed: u64,
+  max_turns: u32,
+}
+
+const SLOTS: [Slot; 6] = [Slot::Weapon, Slot::Helmet, Slot::Armor, Slot::Necklace, Slot::Ring, Slot::Boots];
+
+impl Optimizer {
+  pub fn new(metric: Metric) -> Self {
+    Optimizer { metric, minimums: vec![], per_slot: 3, candidates: 20, samples: 1000, seed: 0, max_turns: 30 }
+  }
+
+  pub fn require(mut self, stat: Stat, min: f32) -> Self {
+    self.minimums.push((stat, min));
+    self
+  }
+
+  pub fn per_slot(mut self, per_slot: usize) -> Self {
+    self.per_slot = per_slot;
+    self
+  }
+
+  pub fn candidates(mut self, candidates: usize) -> Self {
+    self.candidates = candidates;
+    self
+  }
+
+  // Every candidate is sampled with the same seeds, so they're compared on
+  // the same rolls.
+  pub fn samples(mut self, samples: u32, seed: u64) -> Self {
+    self.samples = samples;
+    self.seed = seed;
+    self
+  }
+
+  pub fn max_turns(mut self, max_turns: u32) -> Self {
+    self.max_turns = max_turns;
+    self
+  }
+
+  // Builds for `hero` of `roster`, counted in `Roster::build` order, from
+  // `pool` and the gear the hero already wears, best first. Empty if no
+  // build meets the minimums. Skills and passives don't depend on gear, so
+  // `picker`, made from `roster`, serves every build.
+  pub fn optimize(
+    &self,
+    roster: &Roster,
+    picker: &'static AISkillPicker,
+    hero: HeroID,
+    pool: &[Gear],
+  ) -> Result<Vec<Build>, NoSuchHero> {
+    let def = roster.teams.iter().flat_map(|x| &x.heroes).nth(hero).ok_or(NoSuchHero(hero))?;
+    let pool: Vec<Gear> = def.gear.iter().chain(pool).cloned().collect();
+    // Same steps as `HeroDef::to_hero`, without rebuilding the hero each time.
+    let bare = HeroDef { gear: vec![], artifact: None, ..def.clone() }.to_hero(0).stats;
+    let geared = |gear: &[Gear]| {
+      let stats = gear::compile(&bare, gear);
+      match def.artifact {
+        Some(ref artifact) => artifact.apply(&stats),
+        None => stats,
+      }
+    };
+    let base = geared(&[]);
+
+    let mut builds: Vec<Build> = vec![];
+    for_each_combination(&self.prune(&base, &pool, &geared), &mut vec![], &mut |gear| {
+      let stats = geared(gear);
+      if !self.minimums.iter().all(|&(stat, min)| stat.of(&stats) >= min) {
+        return;
+      }
+      let score = gear_score(&base, &stats);
+      let at = builds.iter().position(|x| x.score < score).unwrap_or(builds.len());
+      if at < self.candidates {
+        builds.insert(at, Build { gear: gear.to_vec(), stats, score });
+        builds.truncate(self.candidates);
+      }
+    });
+
+    for build in &mut builds {
+      let mut roster = roster.clone();
+      let def = roster.teams.iter_mut().flat_map(|x| &mut x.heroes).nth(hero).unwrap();
+      def.gear = build.gear.clone();
+      build.score = self.simulate(picker, roster.build().0, hero);
+    }
+    builds.sort_by(|x, y| y.score.partial_cmp(&x.score).unwrap());
+    Ok(builds)
+  }
+
+  // Drop pieces another piece of the same slot and set beats on every
+  // stat, then keep the best `per_slot` by gear score.
+  fn prune(&self, base: &HeroStats, pool: &[Gear], geared: &dyn Fn(&[Gear]) -> HeroStats) -> Vec<Vec<Gear>> {
+    let all = [Stat::Hp, Stat::Atk, Stat::Def, Stat::Spd, Stat::Cc, Stat::Cdmg, Stat::Eff, Stat::Effres];
+
+    SLOTS.iter().map(|&slot| {
+      let mut pieces: Vec<(&Gear, HeroStats)> = pool.iter()
+        .filter(|x| x.slot == slot)
+        .map(|x| (x, geared(std::slice::from_ref(x))))
+        .collect();
+      pieces.sort_by(|x, y| gear_score(base, &y.1).partial_cmp(&gear_score(base, &x.1)).unwrap());
+
+      let mut kept: Vec<&Gear> = vec![];
+      for (i, (piece, mine)) in pieces.iter().enumerate() {
+        // Of identical pieces, the first is kept.
+        let dominated = pieces.iter().enumerate().any(|(j, (other, theirs))| {
+          let at_least = all.iter().all(|x| x.of(theirs) >= x.of(mine));
+          other.set == piece.set && at_least && (j < i || all.iter().any(|x| x.of(theirs) > x.of(mine)))
+        });
+        if !dominated && kept.iter().filter(|x| x.set == piece.set).count() < self.per_slot {
+          kept.push(piece);
+        }
+      }
+      kept.into_iter().cloned().collect()
+    }).collect()
+  }
+
+  fn simulate(&self, picker: &'static AISkillPicker, ss: BattleSnapshot, hero: HeroID) -> f32 {
+    let team = ss.heroes[hero].team;
+    let observer = Arc::new(Mutex::new(OutcomeStats::new(team)));
+    let turns = self.max_turns;
+    scheduler::rng_node_sample(move || battle(picker, turns), ss, Arc::clone(&observer), self.samples, self.seed);
+    scheduler::rng_node_join();
+
+    let stats = observer.lock().unwrap();
+    match self.metric {
+      Metric::WinRate => stats.result_rate(BattleResult::Win),
+      Metric::DamageDealt => stats.damage_dealt(hero),
+      Metric::Survival => 1.0 - stats.death_chance(hero),
+    }
+  }
+}
+
+// Calls `f` with one piece from every slot that has any, for every way to
+// pick them.
+fn for_each_combination(slots: &[Vec<Gear>], gear: &mut Vec<Gear>, f: &mut impl FnMut(&[Gear])) {
+  match slots.split_first() {
+    None => f(gear),
+    Some((pieces, rest)) if pieces.is_empty() => for_each_combination(rest, gear, f),
+    Some((pieces, rest)) => {
+      for piece in pieces {
+        gear.push(piece.clone());
+        for_each_combination(rest, gear, f);
+        gear.pop();
+      }
+    }
+  }
+}
+
+// The usual gear score of what gear adds to `base`: percent stats at face
+// value, flat ATK, HP and DEF as the percent of base they add, SPD and crits
+// weighted up.
+fn gear_score(base: &HeroStats, stats: &HeroStats) -> f32 {
+  let percent = |x: f32, of: f32| if of > 0.0 { (x - of) / of * 100.0 } else { 0.0 };
+  percent(stats.atk, base.atk) + percent(stats.max_hp, base.max_hp) + percent(stats.def, base.def)
+    + (stats.spd - base.spd) * 2.0
+    + (stats.cc - base.cc) * 1.6
+    + (stats.cdmg - base.cdmg) * 1.14
+    + (stats.eff - base.eff)
+    + (stats.effres - base.effres)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    data::Format,
+    gear::{ GearSet, GearStat }
+  };
+
+  const MATCHUP: &str = r#"
+    [[teams]]
+    name = "Ally"
+
+    [[teams.heroes]]
+    name = "Geared"
+    element = "fire"
+    stats = { max_hp = 10000, atk = 1000, def = 500, spd = 110, cc = 0 }
+    skills = [{ name = "S1", components = [{ action = { type = "damage" } }] }]
+
+    [[teams]]
+    name = "Enemy"
+
+    [[teams.heroes]]
+    name = "Dummy"
+    element = "ice"
+    stats = { max_hp = 20000, atk = 500, def = 500, spd = 100, cc = 0 }
+    skills = [{ name = "S1", components = [{ action = { type = "damage" } }] }]
+  "#;
+
+  fn pool() -> Vec<Gear> {
+    vec![
+      Gear::new(Slot::Weapon, GearSet::Attack, GearStat::Atk, 500.0).sub(GearStat::Cc, 10.0),
+      // Worse on every stat than the one above.
+      Gear::new(Slot::Weapon, GearSet::Attack, GearStat::Atk, 400.0).sub(GearStat::Cc, 5.0),
+      Gear::new(Slot::Weapon, GearSet::Speed, GearStat::Atk, 300.0),
+      Gear::new(Slot::Boots, GearSet::Attack, GearStat::AtkPercent, 60.0),
+      Gear::new(Slot::Boots, GearSet::Speed, GearStat::Spd, 45.0),
+    ]
+  }
+
+  #[test]
+  fn test_prune_dominated_pieces() {
+    let roster = Roster::parse(MATCHUP, Format::Toml).unwrap();
+    let base = roster.build().0.heroes[0].stats.clone();
+    let geared = |gear: &[Gear]| gear::compile(&base, gear);
+
+    let slots = Optimizer::new(Metric::WinRate).prune(&base, &pool(), &geared);
+    assert_eq!(slots[0], vec![pool()[0].clone(), pool()[2].clone()]);
+    assert_eq!(slots[5].len(), 2);
+    assert!(slots[1].is_empty());
+  }
+
+  fn picker(roster: &Roster) -> &'static AISkillPicker {
+    Box::leak(Box::new(roster.picker()))
+  }
+
+  #[test]
+  fn test_builds_meet_minimums() {
+    let roster = Roster::parse(MATCHUP, Format::Toml).unwrap();
+    let builds = Optimizer::new(Metric::DamageDealt)
+      .require(Stat::Spd, 150.0)
+      .samples(10, 0)
+      .optimize(&roster, picker(&roster), 0, &pool())
+      .unwrap();
+
+    assert_eq!(builds.len(), 2);
+    for build in &builds {
+      assert!(build.stats.spd >= 150.0);
+      assert!(build.gear.contains(&pool()[4]));
+    }
+    assert!(builds[0].score >= builds[1].score);
+
+    let impossible = Optimizer::new(Metric::WinRate)
+      .require(Stat::Spd, 300.0)
+      .optimize(&roster, picker(&roster), 0, &pool())
+      .unwrap();
+    assert!(impossible.is_empty());
+  }
+
+  #[test]
+  fn test_worn_gear_kept_in_the_running() {
+    let mut roster = Roster::parse(MATCHUP, Format::Toml).unwrap();
+    let ring = Gear::new(Slot::Ring, GearSet::Speed, GearStat::Cc, 30.0);
+    roster.teams[0].heroes[0].gear = vec![ring.clone()];
+
+    let builds = Optimizer::new(Metric::DamageDealt)
+      .candidates(3)
+      .samples(10, 0)
+      .optimize(&roster, picker(&roster), 0, &pool())
+      .unwrap();
+    assert_eq!(builds.len(), 3);
+    assert!(builds.iter().all(|x| x.gear.contains(&ring)));
+  }
+
+  #[test]
+  fn test_unknown_hero() {
+    let roster = Roster::parse(MATCHUP, Format::Toml).unwrap();
+    let result = Optimizer::new(Metric::WinRate).optimize(&roster, picker(&roster), 2, &pool());
+    assert!(matches!(result, Err(NoSuchHero(2))));
+  }
+}
@@ -14,6 +14,10 @@ pub struct RngInstance<'r, T> {
 }
 
 impl<'r, T> RngInstance<'r, T> {
+  pub fn chance(&self) -> f32 {
+    self.chance
+  }
+
   pub fn run_action(self, ss: &mut T) -> RngNode<'r,T> {
     (self.action)(ss)
   }
@@ -116,6 +120,27 @@ impl<'r, T: 'r> RngNode<'r, T> {
   pub fn set_label(self, label: String) -> Self {
     Label(label, Box::new(self))
   }
+
+  // Walk every path on the current thread, calling `visit` with each leaf
+  // and its probability. Meant for small trees such as a single skill.
+  pub fn expand(self, ss: T, visit: &mut impl FnMut(T, f32)) where T: Clone {
+    self.expand_with_chance(ss, 1.0, visit)
+  }
+
+  fn expand_with_chance(self, mut ss: T, probability: f32, visit: &mut impl FnMut(T, f32)) where T: Clone {
+    match self {
+      End => visit(ss, probability),
+      Always(action) => action(&mut ss).expand_with_chance(ss, probability, visit),
+      Two(first, second) => {
+        for instance in [first, second] {
+          let mut ss = ss.clone();
+          let p = probability * instance.chance;
+          instance.run_action(&mut ss).expand_with_chance(ss, p, visit);
+        }
+      }
+      Label(_, next) => next.expand_with_chance(ss, probability, visit),
+    }
+  }
 }
 
 fn then_transform<'r, T: 'r>(
@@ -132,7 +157,14 @@ fn then_transform<'r, T: 'r>(
 pub struct RngNodeNeedOne<'r, T>(RngInstance<'r, T>);
 
 impl<'r, T> RngNodeNeedOne<'r, T> {
+  // Certain outcomes collapse into a single `Always` so they don't double the tree.
   pub fn or(self, f: impl FnOnce(&mut T) -> RngNode<'r, T> + Send + 'r) -> RngNode<'r, T> {
+    if self.0.chance >= 1.0 {
+      return RngNode::Always(self.0.action);
+    }
+    if self.0.chance <= 0.0 {
+      return RngNode::Always(Box::new(f));
+    }
     let chance = 1.0 - self.0.chance;
     RngNode::Two(
       self.0,
@@ -197,4 +229,15 @@ mod tests {
     } else { assert!(false) }
   }
 
+  #[test]
+  fn test_expand() {
+    let node = RngNode::branch_two(0.25, |i: &mut i32| {
+      *i += 1;
+      RngNode::branch_two(1.0, |i: &mut i32| { *i *= 10; End }).or(|_| End)
+    }).or(|i| { *i -= 1; End });
+
+    let mut leaves = vec![];
+    node.expand(0, &mut |i, p| leaves.push((i, p)));
+    assert_eq!(leaves, vec![(10, 0.25), (-1, 0.75)]);
+  }
 }
\ No newline at end of file
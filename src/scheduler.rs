@@ -1,29 +1,58 @@
 use threadpool::ThreadPool;
-use crate::{
-  battle::BattleSnapshot,
-  rng::RngNode
-};
+use crate::rng::RngNode;
 use std::sync::{ Mutex, Arc };
+use std::sync::atomic::{ AtomicI32, Ordering };
 use lazy_static::lazy_static;
-use std::fmt;
-use std::thread;
 
 lazy_static! {
   static ref POOL: Mutex<ThreadPool> = Mutex::new(ThreadPool::new(8));
 }
 
+// Branches deeper than this are abandoned, see `RngObserver::rng_did_truncate`.
+static MAX_DEPTH: AtomicI32 = AtomicI32::new(10);
+
+pub fn set_max_depth(depth: i32) {
+  MAX_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+pub fn set_num_threads(num_threads: usize) {
+  POOL.lock().unwrap().set_num_threads(num_threads);
+}
+
 pub trait RngObserver<T>: Send {
   fn rng_did_reach_label(&mut self, label: String);
-  fn rng_did_reach_end(&mut self, ss: T, depth: i32);
+  // `probability` is the product of every branch chance taken to reach `ss`.
+  fn rng_did_reach_end(&mut self, ss: T, depth: i32, probability: f32);
+  // A branch deeper than the depth limit, abandoned in state `ss`.
+  fn rng_did_truncate(&mut self, _ss: T, _depth: i32, _probability: f32) {}
 
   fn should_pause_branching(&self) -> bool;
 }
 
+// Blocks until every branch spawned by `rng_node_run` has reached an end.
+pub fn rng_node_join() {
+  let pool = POOL.lock().unwrap().clone();
+  pool.join();
+}
+
 pub fn rng_node_run<O, T>(
+  node: RngNode<'static, T>,
+  ss: T,
+  observer: Arc<Mutex<O>>,
+  depth: i32,
+)
+  where O: RngObserver<T> + 'static,
+        T: Clone + Send + 'static
+{
+  run_node(node, ss, observer, depth, 1.0)
+}
+
+fn run_node<O, T>(
   mut node: RngNode<'static, T>,
   mut ss: T,
   observer: Arc<Mutex<O>>,
   depth: i32,
+  probability: f32,
 )
   where O: RngObserver<T> + 'static,
         T: Clone + Send + 'static
@@ -33,14 +62,15 @@ pub fn rng_node_run<O, T>(
   loop {
     match node {
       End => {
-        observer.lock().unwrap().rng_did_reach_end(ss, depth);
+        observer.lock().unwrap().rng_did_reach_end(ss, depth, probability);
         return;
       },
       Always(a1) => {
         node = a1(&mut ss);
       },
       Two(a1, a2) => {
-        if depth > 10 {
+        if depth > MAX_DEPTH.load(Ordering::Relaxed) {
+          observer.lock().unwrap().rng_did_truncate(ss, depth, probability);
           return
         }
         let mut ss1 = ss.clone();
@@ -48,20 +78,18 @@ pub fn rng_node_run<O, T>(
 
         let pool = POOL.lock().unwrap();
 
-        let mut _observer = Arc::clone(&observer);
-
-        // while observer.lock().unwrap().should_pause_branching() {
-        //   thread::park();
-        // }
+        let _observer = Arc::clone(&observer);
 
         pool.execute(move || {
+          let p1 = probability * a1.chance();
           let next = a1.run_action(&mut ss1);
-          rng_node_run(next, ss1, _observer, depth + 1);
+          run_node(next, ss1, _observer, depth + 1, p1);
         });
 
         pool.execute(move || {
+          let p2 = probability * a2.chance();
           let next = a2.run_action(&mut ss2);
-          rng_node_run(next, ss2, observer, depth + 1);
+          run_node(next, ss2, observer, depth + 1, p2);
         });
 
         return
@@ -74,29 +102,91 @@ pub fn rng_node_run<O, T>(
   }
 }
 
-pub struct DataCollector {
-  snapshots: Vec<(i32, BattleSnapshot)>
-}
+// Small deterministic generator (SplitMix64) so sampled runs can be
+// reproduced from a seed.
+pub struct SeededRng(u64);
 
-impl RngObserver<BattleSnapshot> for DataCollector {
-  fn rng_did_reach_label(&mut self, label: String) {
-    println!("Label {}", label)
+impl SeededRng {
+  pub fn new(seed: u64) -> Self {
+    SeededRng(seed)
   }
 
-  fn rng_did_reach_end(&mut self, ss: BattleSnapshot, depth: i32) {
-    println!("{}", ss.heroes[0].stats.hp);
-    self.snapshots.push((depth, ss));
+  pub fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
   }
 
-  fn should_pause_branching(&self) -> bool {
-    false
+  // Uniform in [0, 1).
+  pub fn next_f32(&mut self) -> f32 {
+    (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
   }
 }
 
-impl DataCollector {
-  pub fn new() -> Self {
-    Self {
-      snapshots: vec![]
+// Monte Carlo counterpart of `rng_node_run`: follows `samples` random paths
+// instead of every branch. Each leaf is reported with weight 1 / samples.
+// Sample `i` always uses seed `seed + i`, whatever the number of threads.
+pub fn rng_node_sample<O, T, F>(
+  make_node: F,
+  ss: T,
+  observer: Arc<Mutex<O>>,
+  samples: u32,
+  seed: u64,
+)
+  where O: RngObserver<T> + 'static,
+        T: Clone + Send + 'static,
+        F: Fn() -> RngNode<'static, T> + Send + Sync + 'static
+{
+  let make_node = Arc::new(make_node);
+  let probability = 1.0 / samples as f32;
+  let pool = POOL.lock().unwrap();
+
+  for i in 0..samples {
+    let make_node = Arc::clone(&make_node);
+    let observer = Arc::clone(&observer);
+    let ss = ss.clone();
+    pool.execute(move || {
+      let mut rng = SeededRng::new(seed.wrapping_add(i as u64));
+      sample_node(make_node(), ss, observer, &mut rng, probability);
+    });
+  }
+}
+
+fn sample_node<O, T>(
+  mut node: RngNode<'static, T>,
+  mut ss: T,
+  observer: Arc<Mutex<O>>,
+  rng: &mut SeededRng,
+  probability: f32,
+)
+  where O: RngObserver<T> + 'static
+{
+  use RngNode::*;
+
+  let mut depth = 0;
+  loop {
+    match node {
+      End => {
+        observer.lock().unwrap().rng_did_reach_end(ss, depth, probability);
+        return;
+      },
+      Always(a1) => {
+        node = a1(&mut ss);
+      },
+      Two(a1, a2) => {
+        depth += 1;
+        node = if rng.next_f32() < a1.chance() {
+          a1.run_action(&mut ss)
+        } else {
+          a2.run_action(&mut ss)
+        };
+      },
+      Label(label, next) => {
+        observer.lock().unwrap().rng_did_reach_label(label);
+        node = *next;
+      }
     }
   }
 }
@@ -115,7 +205,7 @@ mod tests {
       unimplemented!()
     }
 
-    fn rng_did_reach_end(&mut self, ss: i32, depth: i32) {
+    fn rng_did_reach_end(&mut self, ss: i32, depth: i32, probability: f32) {
       self.results.push(ss);
       inc_and_notify(self.lock.clone());
     }
@@ -1,68 +1,322 @@
 use crate::{
   rng::*,
-  battle::*
+  battle::*,
+  combat_log::HitKind,
+  condition::Condition
 };
+use serde::{ Serialize, Deserialize };
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Targeting {
+  #[serde(rename = "self_aoe")]
   SelfAOE,
+  #[serde(rename = "enemy_aoe")]
   EnemyAOE,
   SelfSingle,
   EnemySingle,
   HighestAtk,
+  #[serde(rename = "highest_cr")]
   HighestCR,
+  // Enemies struck, not missed, by earlier components of the same skill use.
+  Hit,
+  // Whoever set off a passive: the attacker, the debuffer or the killer, or
+  // for `Trigger::Attack` the enemy attacked.
+  Trigger,
+  // Fallen allies, for revives.
+  DeadAllies,
 }
 
 impl Targeting {
   pub fn get_target(&self, ss: &BattleSnapshot, src: HeroID) -> Vec<HeroID> {
     use Targeting::*;
 
+    let team = ss.heroes[src].team;
+    let forced = ss.skill_use.forced.filter(|&i| ss.heroes[i].alive);
+    if let (Some(forced), EnemySingle | HighestAtk | HighestCR) = (forced, self) {
+      return vec![forced];
+    }
+    let allies = || (0..).zip(&ss.heroes).filter(move |(_, x)| x.alive && x.team == team);
+    let enemies = || (0..).zip(&ss.heroes).filter(move |(_, x)| x.alive && x.team != team);
+    let dead_allies = || (0..).zip(&ss.heroes).filter(move |(_, x)| !x.alive && x.team == team);
+
     match self {
       SelfSingle => vec![src],
-      SelfAOE => vec![],
-      _ => vec![]
+      SelfAOE => allies().map(|(i, _)| i).collect(),
+      EnemyAOE => enemies().map(|(i, _)| i).collect(),
+      EnemySingle => enemies().map(|(i, _)| i).take(1).collect(),
+      HighestAtk => enemies()
+        .max_by(|(_, x), (_, y)| x.stats.atk.partial_cmp(&y.stats.atk).unwrap())
+        .map(|(i, _)| i)
+        .into_iter()
+        .collect(),
+      HighestCR => enemies()
+        .max_by_key(|(_, x)| x.readiness)
+        .map(|(i, _)| i)
+        .into_iter()
+        .collect(),
+      Hit => enemies()
+        .map(|(i, _)| i)
+        .filter(|&i| ss.skill_use.hits.iter().any(|&(x, hit)| x == i && hit != HitKind::Miss))
+        .collect(),
+      Targeting::Trigger => ss.skill_use.trigger.into_iter().filter(|&i| ss.heroes[i].alive).collect(),
+      DeadAllies => dead_allies().map(|(i, _)| i).collect(),
     }
   }
 }
 
+// Ally that follows up with their first skill after a dual attack proc.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DualAtkTarget {
-  NoDualAtk,
   Random,
   HighestAtk
 }
 
-struct DualAtk {
-  chance: f32,
-  target: DualAtkTarget
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DualAtk {
+  pub chance: f32,
+  pub target: DualAtkTarget
 }
 
+// Skills deserialise straight from data files, see `data::Roster`.
+// From code, start with `Skill::new` and add components with `new_component`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Skill {
-  dual_atk_target: DualAtkTarget,
+  pub name: String,
+  // Own turns, counting the one it's used on, before the skill is ready again.
+  #[serde(default, skip_serializing_if = "is_zero")]
+  pub cooldown: u32,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub dual_atk: Option<DualAtk>,
   pub components: Vec<SkillComponent>
 }
 
+fn is_zero(x: &u32) -> bool {
+  *x == 0
+}
+
+fn is_false(x: &bool) -> bool {
+  !*x
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SkillComponent {
-  pub activate_condition: Option<fn(&BattleSnapshot, &Hero, &Hero) -> bool>,
+  #[serde(rename = "condition", default, skip_serializing_if = "Option::is_none")]
+  pub activate_condition: Option<Condition>,
   pub action: SkillAction,
+  #[serde(default = "default_targeting")]
   pub targeting: Targeting
 }
 
-struct DamageAction {
-  pow: f32,
-  atk_rate: f32,
-  def_pen: f32,
+// Components run outside the hero's turn whenever `trigger` fires for them,
+// with `chance`. See `battle::react`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Passive {
+  pub name: String,
+  #[serde(deserialize_with = "crate::data::enum_from_table")]
+  pub trigger: Trigger,
+  #[serde(default = "certain")]
+  pub chance: f32,
+  // Only triggers once per battle, like most revives.
+  #[serde(default, skip_serializing_if = "is_false")]
+  pub once: bool,
+  pub components: Vec<SkillComponent>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+  // Start of the hero's own turn, after effects have ticked.
+  TurnStart,
+  // Targeted by an enemy attack, hit or miss.
+  Attacked,
+  // Attacked an enemy, hit or miss. Once per enemy for AoE skills.
+  Attack,
+  AllyDeath,
+  // A debuff from an enemy landed on the hero.
+  Debuffed,
+  // HP went from at least to below this percentage of max HP.
+  HpBelow(f32),
+  // The hero itself died. The only trigger dead heroes react to, so their
+  // components can revive them with `SelfSingle`.
+  Death,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DotKind {
+  Bleed,
+  Burn,
+  Poison,
+}
+
+impl DotKind {
+  pub fn matches(&self, effect: Effect) -> bool {
+    matches!(
+      (self, effect),
+      (DotKind::Bleed, Effect::Bleed(_)) | (DotKind::Burn, Effect::Burn(_)) | (DotKind::Poison, Effect::Poison)
+    )
+  }
+}
+
+// Stat an amount such as a shield is proportional to.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scaling {
+  CasterAtk,
+  CasterDef,
+  CasterMaxHp,
+  CasterSpd,
+  TargetMaxHp,
+  TargetHp,
+  TargetMissingHp,
+}
+
+impl Scaling {
+  pub fn of(&self, caster: &Hero, target: &Hero) -> f32 {
+    match self {
+      Scaling::CasterAtk => caster.stats.atk,
+      Scaling::CasterDef => caster.stats.def,
+      Scaling::CasterMaxHp => caster.stats.max_hp,
+      Scaling::CasterSpd => caster.stats.spd,
+      Scaling::TargetMaxHp => target.stats.max_hp,
+      Scaling::TargetHp => target.stats.hp,
+      Scaling::TargetMissingHp => target.stats.max_hp - target.stats.hp,
+    }
+  }
+}
+
+fn one() -> u32 {
+  1
+}
+
+fn certain() -> f32 {
+  1.0
 }
 
+// Data files build passives through serde; these are for passives made in
+// code, which only tests do.
+#[cfg(test)]
+impl Passive {
+  pub fn new(name: impl Into<String>, trigger: Trigger) -> Self {
+    Passive { name: name.into(), trigger, chance: 1.0, once: false, components: vec![] }
+  }
+
+  pub fn chance(mut self, chance: f32) -> Self {
+    self.chance = chance;
+    self
+  }
+
+  pub fn once(mut self) -> Self {
+    self.once = true;
+    self
+  }
+
+  pub fn new_component(&mut self, action: impl Into<SkillAction>) -> SkillComponentBuilder<'_> {
+    push_component(&mut self.components, action.into())
+  }
+}
+
+fn default_targeting() -> Targeting {
+  Targeting::EnemySingle
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DamageAction {
+  pub pow: f32,
+  pub atk_rate: f32,
+  pub def_pen: f32,
+  // Percent of the damage dealt the caster heals, on top of
+  // `HeroStats::lifesteal`.
+  pub lifesteal: f32,
+  // Added to the ATK term before `pow`, crits and DEF.
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub scaling: Vec<ScaledTerm>,
+  // Damage multiplier added per debuff on the target.
+  pub per_debuff: f32,
+  // True damage on top, unaffected by DEF, crits and misses.
+  pub fixed: f32,
+}
+
+// `rate` times the `of` stat, e.g. 0.1 of the caster's max HP.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScaledTerm {
+  pub rate: f32,
+  pub of: Scaling,
+}
+
+#[cfg(test)]
 impl DamageAction {
-  fn pow(mut self, pow: f32) -> Self {
+  pub fn pow(mut self, pow: f32) -> Self {
     self.pow = pow;
     self
   }
 
-  fn atk_rate(mut self, atk_rate: f32) -> Self {
+  pub fn atk_rate(mut self, atk_rate: f32) -> Self {
     self.atk_rate = atk_rate;
     self
   }
+
+  pub fn lifesteal(mut self, lifesteal: f32) -> Self {
+    self.lifesteal = lifesteal;
+    self
+  }
+
+  pub fn scaling(mut self, of: Scaling, rate: f32) -> Self {
+    self.scaling.push(ScaledTerm { rate, of });
+    self
+  }
+
+  pub fn per_debuff(mut self, per_debuff: f32) -> Self {
+    self.per_debuff = per_debuff;
+    self
+  }
+
+  pub fn fixed(mut self, fixed: f32) -> Self {
+    self.fixed = fixed;
+    self
+  }
+}
+
+// Rate of the applier's ATK a skill's bleed, burn or regen ticks for.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DotRate(pub f32);
+
+// An effect as a skill gives it. Bleed, burn and regen are an ATK rate until
+// applied, when `resolve` fixes the amount per turn; any other effect is
+// applied as it is.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkillEffect {
+  Bleed(DotRate),
+  Burn(DotRate),
+  Regen(DotRate),
+  #[serde(untagged)]
+  Other(Effect),
+}
+
+impl SkillEffect {
+  pub fn resolve(self, atk: f32) -> Effect {
+    match self {
+      SkillEffect::Bleed(DotRate(rate)) => Effect::Bleed(DotAmount(rate * atk)),
+      SkillEffect::Burn(DotRate(rate)) => Effect::Burn(DotAmount(rate * atk)),
+      SkillEffect::Regen(DotRate(rate)) => Effect::Regen(DotAmount(rate * atk)),
+      SkillEffect::Other(effect) => effect,
+    }
+  }
+}
+
+impl From<Effect> for SkillEffect {
+  fn from(effect: Effect) -> Self {
+    SkillEffect::Other(effect)
+  }
 }
 
 impl From<DamageAction> for SkillAction {
@@ -71,36 +325,141 @@ impl From<DamageAction> for SkillAction {
   }
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
 pub enum SkillAction {
-  Effect { chance: f32, effect: Effect, duration: u32},
+  Effect { chance: f32, effect: SkillEffect, duration: u32},
   Damage(DamageAction),
+  // Damage of `atk_rate` times the caster's ATK that can't miss or crit and
+  // doesn't count as an attack, e.g. to the enemies around the main target.
   Splash { atk_rate: f32, def_pen: f32},
-  None
+  // The caster acts again right after this turn. Gate it with a condition
+  // such as `skill.kill` or `skill.crit`.
+  ExtraTurn,
+  // Remove the target's most recent buffs or debuffs. Some, like `Rage`,
+  // can't be removed.
+  DispelBuffs {
+    #[serde(default = "one")]
+    count: u32,
+  },
+  CleanseDebuffs {
+    #[serde(default = "one")]
+    count: u32,
+  },
+  // Deal the remaining damage of the target's `dot` stacks at once,
+  // removing them.
+  Detonate { dot: DotKind },
+  // Heal of `rate` times the `scaling` stat. Dead heroes can't be healed.
+  Heal { rate: f32, scaling: Scaling },
+  // Shield of `rate` times the `scaling` stat.
+  Shield { rate: f32, scaling: Scaling, duration: u32 },
+  // Bring a dead target back with `rate` of their max HP. Living targets are
+  // left alone.
+  Revive { rate: f32 },
+  // Fill or drain the target's CR gauge by `amount` of a full gauge, e.g.
+  // -0.25 to push them back a quarter.
+  ChangeCr { amount: f32 },
 }
 
+#[cfg(test)]
 impl SkillAction {
-  fn damage() -> DamageAction {
-    DamageAction { pow: 1.0, atk_rate: 1.0, def_pen: 0.0 }
+  pub fn damage() -> DamageAction {
+    DamageAction::default()
+  }
+
+  pub fn effect(effect: impl Into<SkillEffect>, chance: f32, duration: u32) -> Self {
+    SkillAction::Effect { chance, effect: effect.into(), duration }
+  }
+
+  // Who a component built for this action aims at until told otherwise:
+  // the caster for anything helpful, else a single enemy.
+  fn default_targeting(&self) -> Targeting {
+    use SkillAction::*;
+
+    match self {
+      Effect { effect, .. } if !effect.resolve(0.0).is_debuff() => Targeting::SelfSingle,
+      Heal { .. } | Shield { .. } | CleanseDebuffs { .. } => Targeting::SelfSingle,
+      Revive { .. } => Targeting::DeadAllies,
+      _ => Targeting::EnemySingle,
+    }
+  }
+}
+
+impl Default for DamageAction {
+  fn default() -> Self {
+    DamageAction {
+      pow: 1.0,
+      atk_rate: 1.0,
+      def_pen: 0.0,
+      lifesteal: 0.0,
+      scaling: vec![],
+      per_debuff: 0.0,
+      fixed: 0.0,
+    }
   }
 }
 
+// Like the passive builders, only used to make skills in tests.
+#[cfg(test)]
 impl Skill {
-  fn new_component(&mut self, action: SkillAction) -> SkillComponentBuilder {
-    let comp = SkillComponent { activate_condition: None, action: SkillAction::None, targeting: Targeting::EnemySingle };
-    self.components.push(comp);
-    let builder = SkillComponentBuilder { component: self.components.last_mut().unwrap() };
-    builder
+  pub fn new(name: impl Into<String>) -> Self {
+    Skill {
+      name: name.into(),
+      cooldown: 0,
+      dual_atk: None,
+      components: vec![],
+    }
+  }
+
+  pub fn cooldown(mut self, turns: u32) -> Self {
+    self.cooldown = turns;
+    self
+  }
+
+  pub fn dual_attack(mut self, chance: f32, target: DualAtkTarget) -> Self {
+    self.dual_atk = Some(DualAtk { chance, target });
+    self
   }
+
+  // Append a component targeting a single enemy, refined through the
+  // returned builder:
+  //
+  //   skill.new_component(SkillAction::damage().atk_rate(0.9)).targeting(Targeting::EnemyAOE);
+  pub fn new_component(&mut self, action: impl Into<SkillAction>) -> SkillComponentBuilder<'_> {
+    push_component(&mut self.components, action.into())
+  }
+}
+
+// Shared by skills and passives: a component with no condition, aimed
+// according to its action, and a builder to change that.
+#[cfg(test)]
+fn push_component(components: &mut Vec<SkillComponent>, action: SkillAction) -> SkillComponentBuilder<'_> {
+  let targeting = action.default_targeting();
+  components.push(SkillComponent { activate_condition: None, action, targeting });
+  SkillComponentBuilder { component: components.last_mut().unwrap() }
 }
 
+#[cfg(test)]
 pub struct SkillComponentBuilder<'a> {
   component: &'a mut SkillComponent,
 }
 
+#[cfg(test)]
 impl<'a> SkillComponentBuilder<'a> {
-  pub fn activate_condition(&mut self, f: fn(&BattleSnapshot, &Hero, &Hero) -> bool) -> &mut Self {
-    self.component.activate_condition = Some(f);
+  // Re-aims the component for the new action; set targeting after this.
+  pub fn action(&mut self, action: impl Into<SkillAction>) -> &mut Self {
+    self.component.action = action.into();
+    self.component.targeting = self.component.action.default_targeting();
     self
   }
-}
 
+  pub fn targeting(&mut self, targeting: Targeting) -> &mut Self {
+    self.component.targeting = targeting;
+    self
+  }
+
+  pub fn activate_condition(&mut self, cond: Condition) -> &mut Self {
+    self.component.activate_condition = Some(cond);
+    self
+  }
+}
@@ -0,0 +1,223 @@
+use crate::{
+  battle::*,
+  scheduler::SeededRng
+};
+
+// Turn order on CR alone, the way `battle` hands out turns: nothing else
+// happens between turns, so no skill pushes or pulls anyone's gauge.
+
+// Starting CR of a hero, in readiness units, uniform between `min` and `max`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StartCr {
+  pub min: u32,
+  pub max: u32,
+}
+
+impl StartCr {
+  pub fn fixed(readiness: u32) -> Self {
+    StartCr { min: readiness, max: readiness }
+  }
+
+  pub fn range(min: u32, max: u32) -> Self {
+    StartCr { min: u32::min(min, CR_FULL), max: u32::min(max, CR_FULL) }
+  }
+
+  // `steps` evenly spaced values covering the range, ends included.
+  fn values(&self, steps: u32) -> Vec<u32> {
+    if self.min >= self.max || steps < 2 {
+      return vec![self.min];
+    }
+    (0..steps).map(|i| self.min + (self.max - self.min) * i / (steps - 1)).collect()
+  }
+}
+
+pub struct TurnOrder {
+  // Heroes taking the first turns -> probability, most likely first.
+  pub orders: Vec<(Vec<HeroID>, f32)>,
+}
+
+impl TurnOrder {
+  // Chance `hero` takes turn `turn`, counting from 0.
+  pub fn chance_at(&self, turn: usize, hero: HeroID) -> f32 {
+    self.orders.iter().filter(|(x, _)| x.get(turn) == Some(&hero)).fold(0.0, |acc, (_, p)| acc + p)
+  }
+
+  // Chance `hero` gets a turn before `other` does, within the turns counted.
+  pub fn chance_before(&self, hero: HeroID, other: HeroID) -> f32 {
+    self.orders.iter()
+      .filter(|(x, _)| match (x.iter().position(|&i| i == hero), x.iter().position(|&i| i == other)) {
+        (Some(a), Some(b)) => a < b,
+        (Some(_), None) => true,
+        _ => false,
+      })
+      .fold(0.0, |acc, (_, p)| acc + p)
+  }
+}
+
+// Distribution of who takes the first `turns` turns of `ss`, with every hero
+// starting at a CR from `starts`. Ranges are split into `steps` equally likely
+// values. Only heroes with a range are varied; if that makes more than
+// `MAX_STARTS` combinations, that many are sampled instead. None unless
+// `starts` has one entry per hero.
+pub fn turn_order(ss: &BattleSnapshot, starts: &[StartCr], turns: usize, steps: u32) -> Option<TurnOrder> {
+  if starts.len() != ss.heroes.len() {
+    return None;
+  }
+  let values: Vec<Vec<u32>> = starts.iter().map(|x| x.values(steps)).collect();
+  let ranged: Vec<HeroID> = (0..values.len()).filter(|&i| values[i].len() > 1).collect();
+  let mut orders: Vec<(Vec<HeroID>, f32)> = vec![];
+
+  // Turn order only moves readiness, so one copy is reset for every start.
+  let mut ss = ss.clone();
+  let mut count = |ss: &mut BattleSnapshot, start: &[u32], probability: f32| {
+    for (hero, value) in ss.heroes.iter_mut().zip(&values) {
+      hero.readiness = value[0];
+    }
+    for (&hero, &readiness) in ranged.iter().zip(start) {
+      ss.heroes[hero].readiness = readiness;
+    }
+    let order = first_turns(ss, turns);
+    match orders.iter_mut().find(|(x, _)| *x == order) {
+      Some((_, p)) => *p += probability,
+      None => orders.push((order, probability)),
+    }
+  };
+
+  let options: Vec<&[u32]> = ranged.iter().map(|&i| values[i].as_slice()).collect();
+  let combinations = options.iter().try_fold(1usize, |acc, x| acc.checked_mul(x.len()));
+  match combinations {
+    Some(x) if x <= MAX_STARTS => {
+      for_each_start(&options, &mut vec![], 1.0, &mut |start, probability| count(&mut ss, start, probability));
+    }
+    _ => {
+      let mut rng = SeededRng::new(0);
+      for _ in 0..MAX_STARTS {
+        let start: Vec<u32> = options.iter().map(|x| x[rng.next_u64() as usize % x.len()]).collect();
+        count(&mut ss, &start, 1.0 / MAX_STARTS as f32);
+      }
+    }
+  }
+  orders.sort_by(|x, y| y.1.partial_cmp(&x.1).unwrap());
+  Some(TurnOrder { orders })
+}
+
+// Starting CRs `turn_order` tries before it switches to sampling.
+const MAX_STARTS: usize = 4096;
+
+// Least SPD `hero` needs to act before `other` however their starting CR
+// rolls, or None if no SPD will do. `starts` has one entry per hero.
+pub fn min_spd_to_outspeed(ss: &BattleSnapshot, starts: &[StartCr], hero: HeroID, other: HeroID) -> Option<f32> {
+  if hero == other || starts.len() != ss.heroes.len() || hero >= starts.len() || other >= starts.len() {
+    return None;
+  }
+  if !ss.heroes[hero].alive || !ss.heroes[other].alive {
+    return None;
+  }
+  let mut ss = ss.clone();
+  // Worst case: the slowest start for `hero`, the fastest for `other`.
+  ss.heroes[hero].readiness = starts[hero].min;
+  ss.heroes[other].readiness = starts[other].max;
+  let outspeeds = |spd: u32| {
+    let mut ss = ss.clone();
+    ss.heroes[hero].stats.spd = spd as f32;
+    loop {
+      match first_turns(&mut ss, 1).first() {
+        Some(&x) if x == hero => return true,
+        Some(&x) if x == other => return false,
+        Some(_) => (),
+        None => return false,
+      }
+    }
+  };
+
+  let (mut low, mut high) = (1, MAX_SPD);
+  if !outspeeds(high) {
+    return None;
+  }
+  while low < high {
+    let mid = (low + high) / 2;
+    if outspeeds(mid) { high = mid } else { low = mid + 1 }
+  }
+  Some(low as f32)
+}
+
+// Upper end of the search in `min_spd_to_outspeed`.
+const MAX_SPD: u32 = 10000;
+
+// Fewer than `turns` if nobody is left alive to take them.
+fn first_turns(ss: &mut BattleSnapshot, turns: usize) -> Vec<HeroID> {
+  (0..turns).map_while(|_| {
+    ss.advance_readiness();
+    let hero = ss.get_turn_hero_id()?;
+    ss.heroes[hero].readiness = 0;
+    Some(hero)
+  }).collect()
+}
+
+fn for_each_start(values: &[&[u32]], start: &mut Vec<u32>, probability: f32, f: &mut impl FnMut(&[u32], f32)) {
+  match values.split_first() {
+    None => f(start, probability),
+    Some((options, rest)) => {
+      let p = probability / options.len() as f32;
+      for &value in options.iter() {
+        start.push(value);
+        for_each_start(rest, start, p, f);
+        start.pop();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hero(spd: f32) -> Hero {
+    let mut hero = test_hero(1);
+    hero.stats.spd = spd;
+    hero
+  }
+
+  #[test]
+  fn test_turn_order_with_start_ranges() {
+    let ss = BattleSnapshot::new(vec![hero(100.0), hero(200.0)]);
+
+    let fixed = turn_order(&ss, &[StartCr::fixed(0), StartCr::fixed(0)], 3, 1).unwrap();
+    assert_eq!(fixed.orders, vec![(vec![1, 1, 0], 1.0)]);
+
+    // Hero 0 beats hero 1 to the first turn only from 60% CR up.
+    let ranged = turn_order(&ss, &[StartCr::range(0, 8000), StartCr::fixed(0)], 1, 5).unwrap();
+    assert_eq!(ranged.chance_at(0, 0), 0.4);
+    assert_eq!(ranged.chance_before(1, 0), 0.6);
+    assert!(turn_order(&ss, &[StartCr::fixed(0)], 1, 1).is_none());
+
+    let mut dead = ss.clone();
+    dead.heroes.iter_mut().for_each(|x| x.alive = false);
+    let none = turn_order(&dead, &[StartCr::fixed(0), StartCr::fixed(0)], 3, 1).unwrap();
+    assert_eq!(none.orders, vec![(vec![], 1.0)]);
+  }
+
+  #[test]
+  fn test_turn_order_samples_large_matchups() {
+    let ss = BattleSnapshot::new((0..10).map(|i| hero(100.0 + i as f32)).collect());
+    let starts = vec![StartCr::range(0, CR_FULL); 10];
+
+    // 5^10 starts to enumerate, so MAX_STARTS are sampled.
+    let order = turn_order(&ss, &starts, 1, 5).unwrap();
+    let total: f32 = order.orders.iter().map(|(_, p)| p).sum();
+    assert!((total - 1.0).abs() < 1e-3);
+    assert!(order.orders.len() <= 10);
+  }
+
+  #[test]
+  fn test_min_spd_to_outspeed() {
+    let ss = BattleSnapshot::new(vec![hero(100.0), hero(150.0)]);
+    let starts = [StartCr::fixed(0), StartCr::range(0, 1000)];
+
+    // 9000 CR to go at 150 SPD: faster than 10000 CR needs more than 166.7.
+    assert_eq!(min_spd_to_outspeed(&ss, &starts, 0, 1), Some(167.0));
+    assert_eq!(min_spd_to_outspeed(&ss, &[StartCr::fixed(0), StartCr::fixed(CR_FULL)], 0, 1), None);
+    assert_eq!(min_spd_to_outspeed(&ss, &starts, 0, 0), None);
+    assert_eq!(min_spd_to_outspeed(&ss, &starts[..1], 0, 1), None);
+  }
+}
@@ -0,0 +1,383 @@
+use crate::{
+  battle::{ BattleResult, BattleSnapshot, HeroID },
+  combat_log::{ BattleEvent, CombatLog },
+  scheduler::RngObserver
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Discrete probability distribution keyed on a whole number (HP, turn...).
+type Distribution = BTreeMap<u32, f32>;
+
+#[derive(Clone, Default)]
+struct HeroOutcome {
+  name: String,
+  weighted_hp: f32,
+  // Probability weighted damage dealt, all of it and just over time.
+  dealt: f32,
+  dot_dealt: f32,
+  // Probability weighted HP restored by the hero and healing wasted on
+  // full HP.
+  healed: f32,
+  overheal: f32,
+  death: f32,
+  hp: Distribution,
+  death_turn: Distribution,
+}
+
+// Probability weighted aggregate over every leaf of a battle tree. Leaves are
+// folded in as they arrive; only the `max_replays` most likely logs are kept.
+pub struct OutcomeStats {
+  team: u32,
+  total: f32,
+  truncated: f32,
+  leaves: usize,
+  win: f32,
+  draw: f32,
+  lose: f32,
+  heroes: Vec<HeroOutcome>,
+  win_turn: Distribution,
+  max_replays: usize,
+  replays: Vec<(f32, CombatLog)>,
+}
+
+impl OutcomeStats {
+  // `team` is the side results are reported for.
+  pub fn new(team: u32) -> Self {
+    OutcomeStats {
+      team,
+      total: 0.0,
+      truncated: 0.0,
+      leaves: 0,
+      win: 0.0,
+      draw: 0.0,
+      lose: 0.0,
+      heroes: vec![],
+      win_turn: Distribution::new(),
+      max_replays: 0,
+      replays: vec![],
+    }
+  }
+
+  pub fn with_replays(mut self, max_replays: usize) -> Self {
+    self.max_replays = max_replays;
+    self
+  }
+
+  pub fn add_outcome(&mut self, ss: &BattleSnapshot, probability: f32) {
+    if self.heroes.len() < ss.heroes.len() {
+      self.heroes.resize(ss.heroes.len(), HeroOutcome::default());
+      for (hero, outcome) in ss.heroes.iter().zip(&mut self.heroes) {
+        outcome.name = hero.name.clone();
+      }
+    }
+    self.total += probability;
+    self.leaves += 1;
+
+    match ss.result(self.team) {
+      BattleResult::Win => {
+        self.win += probability;
+        *self.win_turn.entry(ss.turn).or_insert(0.0) += probability;
+      }
+      BattleResult::Draw => self.draw += probability,
+      BattleResult::Lose => self.lose += probability,
+    }
+
+    for (hero, outcome) in ss.heroes.iter().zip(&mut self.heroes) {
+      let hp = f32::max(0.0, hero.stats.hp);
+      outcome.weighted_hp += hp * probability;
+      *outcome.hp.entry(hp.round() as u32).or_insert(0.0) += probability;
+    }
+
+    let mut turn = 0;
+    // A revived hero can die more than once; only a death that stuck counts.
+    let mut died = vec![None; ss.heroes.len()];
+    for event in ss.log.events() {
+      match *event {
+        BattleEvent::TurnStart { turn: t, .. } => turn = t,
+        BattleEvent::Damage { src: Some(src), amount, .. } => self.heroes[src].dealt += amount * probability,
+        BattleEvent::DotDamage { src: Some(src), amount, .. } => {
+          self.heroes[src].dealt += amount * probability;
+          self.heroes[src].dot_dealt += amount * probability;
+        }
+        BattleEvent::Heal { src: Some(src), amount, overheal, .. } => {
+          self.heroes[src].healed += amount * probability;
+          self.heroes[src].overheal += overheal * probability;
+        }
+        BattleEvent::Death { hero } => died[hero] = Some(turn),
+        _ => (),
+      }
+    }
+    for (hero, turn) in died.into_iter().enumerate() {
+      if let (false, Some(turn)) = (ss.heroes[hero].alive, turn) {
+        let outcome = &mut self.heroes[hero];
+        outcome.death += probability;
+        *outcome.death_turn.entry(turn).or_insert(0.0) += probability;
+      }
+    }
+
+    if self.max_replays > 0 {
+      let at = self.replays.iter().position(|(p, _)| *p < probability).unwrap_or(self.replays.len());
+      if at < self.max_replays {
+        self.replays.insert(at, (probability, ss.log.clone()));
+        self.replays.truncate(self.max_replays);
+      }
+    }
+  }
+
+  // Probability mass that reached a leaf. Less than 1 when the tree was cut
+  // short by the depth limit; every other figure is normalised by it.
+  pub fn coverage(&self) -> f32 {
+    self.total
+  }
+
+  // Probability mass abandoned at the depth limit.
+  pub fn truncated(&self) -> f32 {
+    self.truncated
+  }
+
+  pub fn leaves(&self) -> usize {
+    self.leaves
+  }
+
+  fn normalize(&self, p: f32) -> f32 {
+    if self.total > 0.0 { p / self.total } else { 0.0 }
+  }
+
+  pub fn result_rate(&self, result: BattleResult) -> f32 {
+    self.normalize(match result {
+      BattleResult::Win => self.win,
+      BattleResult::Draw => self.draw,
+      BattleResult::Lose => self.lose,
+    })
+  }
+
+  pub fn expected_hp(&self, hero: HeroID) -> f32 {
+    self.normalize(self.heroes[hero].weighted_hp)
+  }
+
+  // Expected damage `hero` deals over the battle, including damage over
+  // time from effects they applied.
+  pub fn damage_dealt(&self, hero: HeroID) -> f32 {
+    self.normalize(self.heroes[hero].dealt)
+  }
+
+  pub fn dot_damage_dealt(&self, hero: HeroID) -> f32 {
+    self.normalize(self.heroes[hero].dot_dealt)
+  }
+
+  // Expected HP `hero` restores to allies and themselves over the battle.
+  pub fn healing_done(&self, hero: HeroID) -> f32 {
+    self.normalize(self.heroes[hero].healed)
+  }
+
+  pub fn overheal(&self, hero: HeroID) -> f32 {
+    self.normalize(self.heroes[hero].overheal)
+  }
+
+  pub fn death_chance(&self, hero: HeroID) -> f32 {
+    self.normalize(self.heroes[hero].death)
+  }
+
+  // Turn on which `hero` died -> probability.
+  pub fn death_turns(&self, hero: HeroID) -> Vec<(u32, f32)> {
+    self.distribution(&self.heroes[hero].death_turn)
+  }
+
+  // Turn on which the battle was won -> probability.
+  pub fn win_turns(&self) -> Vec<(u32, f32)> {
+    self.distribution(&self.win_turn)
+  }
+
+  // Lowest HP that `hero` ends at or below with probability `q` (0..=1).
+  pub fn hp_percentile(&self, hero: HeroID, q: f32) -> u32 {
+    let mut acc = 0.0;
+    for (&hp, &p) in &self.heroes[hero].hp {
+      acc += self.normalize(p);
+      if acc >= q - f32::EPSILON {
+        return hp;
+      }
+    }
+    self.heroes[hero].hp.keys().next_back().copied().unwrap_or(0)
+  }
+
+  pub fn replays(&self) -> &[(f32, CombatLog)] {
+    &self.replays
+  }
+
+  // Indexed like the heroes in the logs of `replays`.
+  pub fn names(&self) -> Vec<String> {
+    self.heroes.iter().map(|x| x.name.clone()).collect()
+  }
+
+  fn distribution(&self, dist: &Distribution) -> Vec<(u32, f32)> {
+    dist.iter().map(|(&k, &p)| (k, self.normalize(p))).collect()
+  }
+
+  pub fn summary(&self) -> StatsSummary {
+    StatsSummary {
+      outcomes: self.leaves(),
+      coverage: self.coverage(),
+      truncated: self.truncated(),
+      win: self.result_rate(BattleResult::Win),
+      draw: self.result_rate(BattleResult::Draw),
+      lose: self.result_rate(BattleResult::Lose),
+      heroes: (0..self.heroes.len()).map(|hero| HeroSummary {
+        name: self.heroes[hero].name.clone(),
+        expected_hp: self.expected_hp(hero),
+        hp_p10: self.hp_percentile(hero, 0.1),
+        hp_p50: self.hp_percentile(hero, 0.5),
+        hp_p90: self.hp_percentile(hero, 0.9),
+        damage_dealt: self.damage_dealt(hero),
+        dot_damage_dealt: self.dot_damage_dealt(hero),
+        healing_done: self.healing_done(hero),
+        overheal: self.overheal(hero),
+        death_chance: self.death_chance(hero),
+        death_turns: self.death_turns(hero),
+      }).collect(),
+      win_turns: self.win_turns(),
+    }
+  }
+}
+
+// Plain data version of `OutcomeStats` for machine readable output.
+#[derive(Serialize)]
+pub struct StatsSummary {
+  pub outcomes: usize,
+  pub coverage: f32,
+  pub truncated: f32,
+  pub win: f32,
+  pub draw: f32,
+  pub lose: f32,
+  pub heroes: Vec<HeroSummary>,
+  pub win_turns: Vec<(u32, f32)>,
+}
+
+#[derive(Serialize)]
+pub struct HeroSummary {
+  pub name: String,
+  pub expected_hp: f32,
+  pub hp_p10: u32,
+  pub hp_p50: u32,
+  pub hp_p90: u32,
+  pub damage_dealt: f32,
+  pub dot_damage_dealt: f32,
+  pub healing_done: f32,
+  pub overheal: f32,
+  pub death_chance: f32,
+  pub death_turns: Vec<(u32, f32)>,
+}
+
+impl RngObserver<BattleSnapshot> for OutcomeStats {
+  fn rng_did_reach_label(&mut self, _label: String) {}
+
+  fn rng_did_reach_end(&mut self, ss: BattleSnapshot, _depth: i32, probability: f32) {
+    self.add_outcome(&ss, probability);
+  }
+
+  fn rng_did_truncate(&mut self, _ss: BattleSnapshot, _depth: i32, probability: f32) {
+    self.truncated += probability;
+  }
+
+  fn should_pause_branching(&self) -> bool {
+    false
+  }
+}
+
+impl fmt::Display for OutcomeStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(
+      f, "{} outcomes, {:.2}% coverage, {:.2}% cut at depth limit",
+      self.leaves(), self.coverage() * 100.0, self.truncated() * 100.0
+    )?;
+    writeln!(
+      f, "win {:.2}%  draw {:.2}%  lose {:.2}%",
+      self.result_rate(BattleResult::Win) * 100.0,
+      self.result_rate(BattleResult::Draw) * 100.0,
+      self.result_rate(BattleResult::Lose) * 100.0
+    )?;
+    for hero in 0..self.heroes.len() {
+      writeln!(
+        f, "{} ({}): hp {:.0} (p10 {} / p50 {} / p90 {}), deals {:.0} ({:.0} over time), dies {:.2}%",
+        self.heroes[hero].name,
+        hero,
+        self.expected_hp(hero),
+        self.hp_percentile(hero, 0.1),
+        self.hp_percentile(hero, 0.5),
+        self.hp_percentile(hero, 0.9),
+        self.damage_dealt(hero),
+        self.dot_damage_dealt(hero),
+        self.death_chance(hero) * 100.0
+      )?;
+      if self.healing_done(hero) > 0.0 {
+        writeln!(f, "  heals {:.0} ({:.0} overheal)", self.healing_done(hero), self.overheal(hero))?;
+      }
+    }
+    for (turn, p) in self.win_turns() {
+      writeln!(f, "won on turn {}: {:.2}%", turn, p * 100.0)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::battle::*;
+
+  fn hero(team: u32, hp: f32) -> Hero {
+    let mut hero = test_hero(team);
+    hero.stats = HeroStats { max_hp: 1000.0, hp, atk: 0.0, ..test_stats() };
+    hero.alive = hp > 0.0;
+    hero
+  }
+
+  #[test]
+  fn test_weighted_outcomes() {
+    let mut stats = OutcomeStats::new(1).with_replays(1);
+
+    let mut won = BattleSnapshot::new(vec![hero(1, 600.0), hero(2, 0.0)]);
+    won.turn = 2;
+    won.log.push(BattleEvent::TurnStart { turn: 2, hero: 0 });
+    won.log.push(BattleEvent::Damage { src: Some(0), target: 1, amount: 800.0 });
+    won.log.push(BattleEvent::DotDamage { src: Some(0), target: 1, effect: Effect::Burn(DotAmount(200.0)), amount: 200.0 });
+    won.log.push(BattleEvent::Death { hero: 1 });
+    stats.add_outcome(&won, 0.25);
+
+    let mut drawn = BattleSnapshot::new(vec![hero(1, 200.0), hero(2, 500.0)]);
+    drawn.log.push(BattleEvent::Heal { src: Some(0), target: 0, amount: 100.0, overheal: 20.0 });
+    stats.add_outcome(&drawn, 0.75);
+
+    assert_eq!(stats.result_rate(BattleResult::Win), 0.25);
+    assert_eq!(stats.result_rate(BattleResult::Draw), 0.75);
+    assert_eq!(stats.expected_hp(0), 300.0);
+    assert_eq!(stats.death_chance(1), 0.25);
+    assert_eq!(stats.damage_dealt(0), 250.0);
+    assert_eq!(stats.dot_damage_dealt(0), 50.0);
+    assert_eq!(stats.healing_done(0), 75.0);
+    assert_eq!(stats.overheal(0), 15.0);
+    assert_eq!(stats.death_turns(1), vec![(2, 0.25)]);
+    assert_eq!(stats.win_turns(), vec![(2, 0.25)]);
+    assert_eq!(stats.hp_percentile(0, 0.5), 200);
+    assert_eq!(stats.hp_percentile(0, 1.0), 600);
+    assert_eq!(stats.replays().len(), 1);
+    assert_eq!(stats.replays()[0].0, 0.75);
+  }
+
+  #[test]
+  fn test_coverage_and_truncation() {
+    let mut stats = OutcomeStats::new(1);
+    let won = BattleSnapshot::new(vec![hero(1, 600.0), hero(2, 0.0)]);
+    stats.add_outcome(&won, 0.5);
+    stats.rng_did_truncate(won.clone(), 12, 0.25);
+    stats.add_outcome(&won, 0.25);
+
+    assert_eq!(stats.leaves(), 2);
+    assert_eq!(stats.coverage(), 0.75);
+    assert_eq!(stats.truncated(), 0.25);
+    // Rates are of the outcomes reached, not of the whole tree.
+    assert_eq!(stats.result_rate(BattleResult::Win), 1.0);
+    assert!(stats.to_string().starts_with("2 outcomes, 75.00% coverage, 25.00% cut at depth limit"));
+  }
+}